@@ -0,0 +1,146 @@
+use crate::errors::YuchiError;
+
+/// Default lifetime of a cached vault key, in seconds.
+pub const DEFAULT_TTL_SECS: u64 = 15 * 60;
+
+#[cfg(unix)]
+mod imp {
+    use super::YuchiError;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use std::fs;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::os::unix::process::CommandExt;
+    use std::path::PathBuf;
+    use std::process::{Command, Stdio};
+    use std::time::Duration;
+
+    fn socket_path() -> Option<PathBuf> {
+        let dir = dirs::runtime_dir().or_else(|| {
+            confy::get_configuration_file_path("yuchi", "config")
+                .ok()
+                .and_then(|p| p.parent().map(|p| p.to_path_buf()))
+        })?;
+        Some(dir.join("yuchi-agent.sock"))
+    }
+
+    fn request(line: &str) -> Option<String> {
+        let mut stream = UnixStream::connect(socket_path()?).ok()?;
+        stream.set_read_timeout(Some(Duration::from_secs(2))).ok()?;
+        writeln!(stream, "{}", line).ok()?;
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).ok()?;
+        Some(reply.trim().to_string())
+    }
+
+    pub fn fetch(salt: &str) -> Option<[u8; 32]> {
+        let reply = request(&format!("GET {}", salt))?;
+        let bytes = BASE64.decode(reply).ok()?;
+        bytes.try_into().ok()
+    }
+
+    pub fn start(salt: &str, key: &[u8; 32], ttl_secs: u64) -> Result<(), YuchiError> {
+        stop();
+        let exe = std::env::current_exe()
+            .map_err(|e| YuchiError::io("Failed to locate the yuchi binary", e))?;
+        let mut child = Command::new(exe)
+            .arg("vault-agent")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            // Own process group, so Ctrl-C in the terminal doesn't take the agent down.
+            .process_group(0)
+            .spawn()
+            .map_err(|e| YuchiError::io("Failed to start the vault agent", e))?;
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| YuchiError::Config("Failed to hand the key to the vault agent.".to_string()))?;
+        writeln!(stdin, "{} {} {}", salt, BASE64.encode(key), ttl_secs)
+            .map_err(|e| YuchiError::io("Failed to hand the key to the vault agent", e))?;
+        Ok(())
+    }
+
+    pub fn stop() -> bool {
+        request("STOP").is_some()
+    }
+
+    /// Runs the agent: holds one key in memory and serves it over a user-private socket until it expires.
+    pub fn serve() -> Result<(), YuchiError> {
+        let mut line = String::new();
+        io::stdin()
+            .read_line(&mut line)
+            .map_err(|e| YuchiError::io("Vault agent failed to read the key", e))?;
+        let mut parts = line.split_whitespace();
+        let (salt, key, ttl) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(salt), Some(key), Some(ttl)) => (salt.to_string(), key.to_string(), ttl),
+            _ => return Err(YuchiError::Config("Vault agent received a malformed key.".to_string())),
+        };
+        let ttl: u64 = ttl
+            .parse()
+            .map_err(|_| YuchiError::Config("Vault agent received a malformed TTL.".to_string()))?;
+
+        let path = socket_path()
+            .ok_or_else(|| YuchiError::Config("No directory available for the agent socket.".to_string()))?;
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)
+            .map_err(|e| YuchiError::io(format!("Failed to bind '{}'", path.display()), e))?;
+        crate::vault::set_private(&path)?;
+
+        let expiry_path = path.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(ttl));
+            let _ = fs::remove_file(&expiry_path);
+            std::process::exit(0);
+        });
+
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            // One connection at a time, so a client that never sends its request mustn't hold the agent.
+            if stream.set_read_timeout(Some(Duration::from_secs(2))).is_err() {
+                continue;
+            }
+            let mut request = String::new();
+            if BufReader::new(&stream).read_line(&mut request).is_err() {
+                continue;
+            }
+            let request = request.trim();
+            if request == "STOP" {
+                let _ = writeln!(stream, "OK");
+                break;
+            }
+            let reply = match request.strip_prefix("GET ") {
+                Some(requested) if requested == salt => key.as_str(),
+                _ => "NONE",
+            };
+            let _ = writeln!(stream, "{}", reply);
+        }
+
+        let _ = fs::remove_file(&path);
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::YuchiError;
+
+    pub fn fetch(_salt: &str) -> Option<[u8; 32]> {
+        None
+    }
+
+    pub fn start(_salt: &str, _key: &[u8; 32], _ttl_secs: u64) -> Result<(), YuchiError> {
+        Err(YuchiError::Config("The vault agent is only available on Unix.".to_string()))
+    }
+
+    pub fn stop() -> bool {
+        false
+    }
+
+    pub fn serve() -> Result<(), YuchiError> {
+        start("", &[0; 32], 0)
+    }
+}
+
+pub use imp::{fetch, serve, start, stop};
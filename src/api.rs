@@ -1,331 +1,443 @@
-use crate::config::Config;
 use crate::errors::YuchiError;
-use crate::ui::display_progress;
-use reqwest::blocking::Client;
+use crate::logging;
+use crate::ui::{display_command_result, display_progress};
+use crate::config::{self, Config};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::HeaderMap;
+use reqwest::{Certificate, Identity, Proxy, StatusCode};
 use serde_json::{json, Value};
 use std::fs;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 use base64::engine::general_purpose::STANDARD as BASE64;
 use base64::Engine;
-use crate::commands::run_tool;
+use crate::config::ToolPolicy;
+use crate::tools::{self, Approval, ToolSettings};
 
-// Hardcoded app_id for user auth token flow
+/// Root of the ShapesAI API.
+pub const BASE_URL: &str = "https://api.shapes.inc";
+
+/// Tool-calling round trips allowed per question; the last request offers no tools.
+const MAX_TOOL_ROUNDS: usize = 10;
+
+// Default app_id for user auth token flow, used when no app ID is configured
 pub const APP_ID: &str = "3718bde3-c803-4bfc-b41b-3b5f0aa0ddd8";
 
-// Define tool schemas for ShapesAI API
-fn tool_schemas() -> Vec<Value> {
-    vec![json!({
-        "type": "function",
-        "function": {
-            "name": "run_shell_command",
-            "description": "Run a shell command in the current directory",
-            "parameters": {
-                "type": "object",
-                "properties": {
-                    "command": {
-                        "type": "string",
-                        "description": "The shell command to run (e.g., npm install express)"
-                    }
-                },
-                "required": ["command"]
-            }
-        }
-    })]
+/// How requests to ShapesAI are authenticated.
+pub enum Auth<'a> {
+    ApiKey {
+        api_key: &'a str,
+        user_id: &'a str,
+        channel_id: &'a str,
+    },
+    UserToken {
+        token: &'a str,
+        app_id: &'a str,
+        /// Conversation to talk in; without one ShapesAI picks the user's default.
+        channel_id: Option<&'a str>,
+    },
 }
 
-pub fn ask_shapesai(
-    prompt: &str,
-    api_key: Option<&str>,
-    user_auth_token: Option<&str>,
-    model: &str,
-    user_id: &str,
-    channel_id: &str,
-    image_path: Option<&str>,
-    pb: Option<&indicatif::ProgressBar>,
-) -> Result<String, YuchiError> {
-    let client = Client::new();
-    let mut messages = vec![];
-
-    // Adjust prompt for text extraction if "text" is in the prompt
-    let adjusted_prompt = if image_path.is_some() && prompt.to_lowercase().contains("text") {
-        format!("Extract the text from this image: {}", prompt)
-    } else {
-        prompt.to_string()
-    };
+impl Auth<'_> {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Auth::ApiKey { .. } => "API key",
+            Auth::UserToken { .. } => "user auth token",
+        }
+    }
 
-    if let Some(image_path) = image_path {
-        let path = std::path::Path::new(image_path);
-        if !path.exists() || !path.is_file() {
-            return Err(YuchiError::Image(format!(
-                "Image file '{}' does not exist or is not a file",
-                image_path
-            )));
+    /// Error for a response that rejected these credentials, or `None` for any other status.
+    fn rejection(&self, status: StatusCode) -> Option<YuchiError> {
+        match status {
+            StatusCode::UNAUTHORIZED => Some(YuchiError::Auth(format!(
+                "The {} is invalid or has expired ({}).",
+                self.describe(),
+                status
+            ))),
+            StatusCode::FORBIDDEN => Some(YuchiError::Auth(format!(
+                "The {} was refused ({}); it may have been revoked.",
+                self.describe(),
+                status
+            ))),
+            _ => None,
         }
+    }
 
-        let image_data = fs::read(path).map_err(|e| {
-            YuchiError::Image(format!("Failed to read image file '{}': {}", image_path, e))
-        })?;
-        let base64_image = BASE64.encode(&image_data);
-
-        // Guess MIME type based on extension (PNG or JPEG)
-        let mime_type = match path.extension().and_then(|ext| ext.to_str()) {
-            Some("png") => "image/png",
-            Some("jpg") | Some("jpeg") => "image/jpeg",
-            _ => {
-                return Err(YuchiError::Image(format!(
-                    "Unsupported image format for '{}'. Use PNG or JPEG.",
-                    image_path
-                )))
+    fn apply(&self, request_builder: RequestBuilder) -> RequestBuilder {
+        match self {
+            Auth::ApiKey { api_key, user_id, channel_id } => request_builder
+                .header("X-User-ID", *user_id)
+                .header("X-Channel-ID", *channel_id)
+                .header("Authorization", format!("Bearer {}", api_key)),
+            Auth::UserToken { token, app_id, channel_id } => {
+                let request_builder = request_builder
+                    .header("X-App-ID", *app_id)
+                    .header("X-User-Auth", *token);
+                match channel_id {
+                    Some(channel_id) => request_builder.header("X-Channel-ID", *channel_id),
+                    None => request_builder,
+                }
             }
-        };
-
-        let image_url = format!("data:{};base64,{}", mime_type, base64_image);
-
-        messages.push(json!({
-            "role": "user",
-            "content": [
-                { "type": "text", "text": adjusted_prompt },
-                { "type": "image_url", "image_url": { "url": image_url } }
-            ]
-        }));
-    } else {
-        messages.push(json!({
-            "role": "user",
-            "content": adjusted_prompt
-        }));
+        }
     }
+}
 
-    let mut request_builder = client.post("https://api.shapes.inc/v1/chat/completions");
-
-    if let Some(user_auth_token) = user_auth_token {
-        let app_id = Config::load()?
-            .app_id
-            .ok_or_else(|| YuchiError::Config("No app ID set for user auth token.".to_string()))?;
-        request_builder = request_builder
-            .header("X-App-ID", app_id)
-            .header("X-User-Auth", user_auth_token);
-    } else if let Some(api_key) = api_key {
-        request_builder = request_builder
-            .header("X-User-ID", user_id)
-            .header("X-Channel-ID", channel_id)
-            .header("Authorization", format!("Bearer {}", api_key));
-    } else {
-        return Err(YuchiError::Api(
-            "No API key or user auth token provided.".to_string(),
-        ));
+/// The HTTP client for every request to ShapesAI, built from the network settings:
+/// proxy, extra root certificates, client certificate, timeouts and extra headers.
+pub fn client(config: &Config) -> Result<Client, YuchiError> {
+    let mut builder = Client::builder();
+    if let Some(proxy) = &config.proxy {
+        let proxy = Proxy::all(proxy)
+            .map_err(|e| YuchiError::Config(format!("Invalid proxy '{}': {}", proxy, e)))?;
+        builder = builder.proxy(proxy);
     }
+    if let Some(path) = &config.ca_bundle {
+        let pem = fs::read(path).map_err(|e| YuchiError::io(format!("Failed to read CA bundle '{}'", path), e))?;
+        let certificates = Certificate::from_pem_bundle(&pem)
+            .map_err(|e| YuchiError::Config(format!("Invalid CA bundle '{}': {}", path, e)))?;
+        if certificates.is_empty() {
+            return Err(YuchiError::Config(format!("CA bundle '{}' contains no PEM certificates.", path)));
+        }
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if let Some(path) = &config.client_cert {
+        let pem = fs::read(path)
+            .map_err(|e| YuchiError::io(format!("Failed to read client certificate '{}'", path), e))?;
+        let identity = Identity::from_pem(&pem)
+            .map_err(|e| YuchiError::Config(format!("Invalid client certificate '{}': {}", path, e)))?;
+        builder = builder.identity(identity);
+    }
+    if let Some(secs) = config.connect_timeout {
+        builder = builder.connect_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = config.timeout {
+        builder = builder.timeout(Duration::from_secs(secs));
+    }
+    if let Some(headers) = &config.headers {
+        let mut map = HeaderMap::new();
+        for header in headers {
+            let (name, value) = config::parse_header(header)
+                .ok_or_else(|| YuchiError::Config(format!("Invalid header '{}'. Use `Name: value`.", header)))?;
+            map.append(name, value);
+        }
+        builder = builder.default_headers(map);
+    }
+    builder
+        .build()
+        .map_err(|e| YuchiError::network("Failed to build the HTTP client", e))
+}
 
-    request_builder = request_builder.json(&json!({
-        "model": model,
-        "messages": messages,
-        "tools": tool_schemas(),
-        "tool_choice": "auto"
-    }));
-
-    let pb = pb.cloned().unwrap_or_else(|| display_progress());
-    pb.set_message("Querying ShapesAI...");
-
-    let res = request_builder.send().map_err(|e| {
-        YuchiError::Api(format!("Failed to send request to ShapesAI API: {}", e))
+/// Sends a request, logging it with its status and timing; `context` describes it in errors.
+pub fn send(request_builder: RequestBuilder, context: &str) -> Result<Response, YuchiError> {
+    let (client, request) = request_builder.build_split();
+    let request = request.map_err(|e| YuchiError::network(context, e))?;
+    logging::trace_request(&request);
+    let url = request.url().to_string();
+    let started = Instant::now();
+    let res = client.execute(request).map_err(|e| {
+        log::debug!(url, elapsed_ms = started.elapsed().as_millis() as u64; "request failed: {}", e);
+        YuchiError::network(context, e)
     })?;
+    log::info!(status = res.status().as_u16(), elapsed_ms = started.elapsed().as_millis() as u64; "{}", url);
+    Ok(res)
+}
 
-    if !res.status().is_success() {
-        let status = res.status();
-        let error_body = res.text().unwrap_or_else(|_| "No response body".to_string());
-        pb.finish_and_clear();
-        return Err(YuchiError::Api(match status.as_u16() {
-            429 => "Blame Shapes, I got rate-limited. Try again later.".to_string(),
-            404 => "The resource couldn't be found.".to_string(),
-            403 => "I don't have access to the AccessVerse.".to_string(),
-            _ => format!("API request failed with status: {}. Response: {}", status, error_body),
-        }));
+/// Turns an unsuccessful response into the matching error; `message` describes what failed.
+pub fn error_for_response(res: Response, auth: Option<&Auth>, message: &str) -> YuchiError {
+    let status = res.status();
+    let url = res.url().to_string();
+    let retry_after = res
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+    let body = res.text().unwrap_or_else(|_| "No response body".to_string());
+    logging::trace_response(&url, status, &body);
+
+    if let Some(e) = auth.and_then(|auth| auth.rejection(status)) {
+        return e;
     }
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return YuchiError::RateLimited { retry_after };
+    }
+    YuchiError::Http {
+        status,
+        message: message.to_string(),
+        body,
+    }
+}
 
-    let json: Value = res
-        .json()
-        .map_err(|e| YuchiError::Api(format!("Failed to parse API response: {}", e)))?;
-
-    let tool_calls = json
-        .get("choices")
-        .and_then(|choices| choices.get(0))
-        .and_then(|choice| choice.get("message"))
-        .and_then(|message| message.get("tool_calls"))
-        .and_then(|tool_calls| tool_calls.as_array());
-
-    if let Some(tool_calls) = tool_calls {
-        pb.finish_and_clear(); // Clear progress bar before tool execution
-        messages.push(json!({
-            "role": "assistant",
-            "tool_calls": tool_calls
-        }));
-
-        for tool_call in tool_calls {
-            let tool_call_id = tool_call
-                .get("id")
-                .and_then(|id| id.as_str())
-                .ok_or_else(|| YuchiError::Api("Missing tool call ID".to_string()))?;
-            let arguments = tool_call
-                .get("function")
-                .and_then(|f| f.get("arguments"))
-                .ok_or_else(|| YuchiError::Api("Missing tool arguments".to_string()))?;
-            let args_str = arguments
-                .as_str()
-                .ok_or_else(|| YuchiError::Api("Tool arguments must be a JSON string".to_string()))?;
-            let args: serde_json::Map<String, Value> = serde_json::from_str(args_str).map_err(|e| {
-                YuchiError::Api(format!("Failed to parse tool arguments: {}", e))
-            })?;
-            let command = args
-                .get("command")
-                .and_then(|c| c.as_str())
-                .ok_or_else(|| YuchiError::Api("Missing command parameter".to_string()))?;
-
-            let tool_result = run_tool(command, Some(&pb))?;
-            messages.push(json!({
-                "role": "tool",
-                "tool_call_id": tool_call_id,
-                "content": tool_result
-            }));
-        }
+/// Reads a JSON response body, keeping the underlying transport or parse error.
+pub fn read_json(res: Response, context: &str) -> Result<Value, YuchiError> {
+    let (url, status) = (res.url().to_string(), res.status());
+    let text = res.text().map_err(|e| YuchiError::network(context, e))?;
+    logging::trace_response(&url, status, &text);
+    serde_json::from_str(&text).map_err(|e| YuchiError::json(context, e))
+}
 
-        let mut second_request = client.post("https://api.shapes.inc/v1/chat/completions");
-
-        if let Some(user_auth_token) = user_auth_token {
-            let app_id = Config::load()?
-                .app_id
-                .ok_or_else(|| YuchiError::Config("No app ID set for user auth token.".to_string()))?;
-            second_request = second_request
-                .header("X-App-ID", app_id)
-                .header("X-User-Auth", user_auth_token);
-        } else if let Some(api_key) = api_key {
-            second_request = second_request
-                .header("X-User-ID", user_id)
-                .header("X-Channel-ID", channel_id)
-                .header("Authorization", format!("Bearer {}", api_key));
-        }
+/// Checks that the credentials are accepted for `model` without touching the user's conversation.
+/// The probe goes to a throwaway user and channel ID and carries no tools, so the shape's memory
+/// is not seeded and nothing can be executed locally.
+pub fn validate_credentials(client: &Client, auth: &Auth, model: &str) -> Result<(), YuchiError> {
+    let throwaway_user = Uuid::new_v4().to_string();
+    let throwaway_channel = Uuid::new_v4().to_string();
+    let probe_auth = match auth {
+        Auth::ApiKey { api_key, .. } => Auth::ApiKey {
+            api_key,
+            user_id: &throwaway_user,
+            channel_id: &throwaway_channel,
+        },
+        Auth::UserToken { token, app_id, .. } => Auth::UserToken {
+            token,
+            app_id,
+            channel_id: Some(&throwaway_channel),
+        },
+    };
+    let request_builder = probe_auth.apply(client.post(format!("{}/v1/chat/completions", BASE_URL)));
 
-        second_request = second_request.json(&json!({
+    let res = send(
+        request_builder.json(&json!({
             "model": model,
-            "messages": messages,
+            "messages": [{ "role": "user", "content": "ping" }],
             "tool_choice": "none"
-        }));
+        })),
+        "Failed to send request to ShapesAI API",
+    )?;
 
-        pb.set_message("Querying ShapesAI..."); // Restart progress bar
-        let second_res = second_request.send().map_err(|e| {
-            YuchiError::Api(format!("Failed to send second request to ShapesAI API: {}", e))
-        })?;
+    if res.status().is_success() {
+        return Ok(());
+    }
+    let message = match res.status() {
+        StatusCode::NOT_FOUND => format!("The model '{}' couldn't be found.", model),
+        _ => "API request failed".to_string(),
+    };
+    Err(error_for_response(res, Some(auth), &message))
+}
 
-        pb.finish_and_clear();
+/// A tool call's arguments, which the API sends as a JSON string; a missing or empty string means none.
+fn tool_arguments(tool_call: &Value) -> Result<Value, String> {
+    match tool_call.get("function").and_then(|f| f.get("arguments")) {
+        None | Some(Value::Null) => Ok(json!({})),
+        Some(Value::String(text)) if text.trim().is_empty() => Ok(json!({})),
+        Some(Value::String(text)) => serde_json::from_str(text).map_err(|e| format!("not valid JSON ({})", e)),
+        Some(other) => Ok(other.clone()),
+    }
+}
 
-        if !second_res.status().is_success() {
-            let status = second_res.status();
-            let error_body = second_res
-                .text()
-                .unwrap_or_else(|_| "No response body".to_string());
-            return Err(YuchiError::Api(format!(
-                "Second API request failed with status: {}. Response: {}",
-                status, error_body
-            )));
-        }
+/// Where the time for one question went.
+#[derive(Default)]
+pub struct Latency {
+    /// Each API round trip, including reading the reply.
+    pub requests: Vec<Duration>,
+    /// Running tools, including waiting for approval.
+    pub tools: Duration,
+}
 
-        let second_json: Value = second_res.json().map_err(|e| {
-            YuchiError::Api(format!("Failed to parse second API response: {}", e))
-        })?;
-        let reply = second_json
-            .get("choices")
-            .and_then(|choices| choices.get(0))
-            .and_then(|choice| choice.get("message"))
-            .and_then(|message| message.get("content"))
-            .and_then(|content| content.as_str())
-            .unwrap_or("No response from tool execution.")
-            .to_string();
+pub struct Reply {
+    pub text: String,
+    pub latency: Latency,
+}
 
-        return Ok(reply);
-    }
+/// The message that asks `prompt`, with the image at `image_path` attached if there is one.
+pub fn question(prompt: &str, image_path: Option<&str>) -> Result<Value, YuchiError> {
+    // Adjust prompt for text extraction if "text" is in the prompt
+    let adjusted_prompt = if image_path.is_some() && prompt.to_lowercase().contains("text") {
+        format!("Extract the text from this image: {}", prompt)
+    } else {
+        prompt.to_string()
+    };
 
-    // Fallback for <function> tag format
-    let content = json
-        .get("choices")
-        .and_then(|choices| choices.get(0))
-        .and_then(|choice| choice.get("message"))
-        .and_then(|message| message.get("content"))
-        .and_then(|content| content.as_str())
-        .unwrap_or("");
-
-    if content.starts_with("<function>") && content.ends_with("</function>") {
-        pb.finish_and_clear(); // Clear progress bar before tool execution
-        let command = content
-            .strip_prefix("<function>")
-            .and_then(|s| s.strip_suffix("</function>"))
-            .ok_or_else(|| YuchiError::Api("Invalid function tag format".to_string()))?;
-
-        let args: Value = serde_json::from_str(command)
-            .map_err(|e| YuchiError::Api(format!("Failed to parse function arguments: {}", e)))?;
-        let command = args
-            .get("command")
-            .and_then(|c| c.as_str())
-            .ok_or_else(|| YuchiError::Api("Missing command parameter".to_string()))?;
-
-        let tool_result = run_tool(command, Some(&pb))?;
-        messages.push(json!({
-            "role": "tool",
-            "tool_call_id": "fallback",
-            "content": tool_result
+    let Some(image_path) = image_path else {
+        return Ok(json!({
+            "role": "user",
+            "content": adjusted_prompt
         }));
+    };
+    let path = std::path::Path::new(image_path);
+    if !path.exists() || !path.is_file() {
+        return Err(YuchiError::Image(format!(
+            "Image file '{}' does not exist or is not a file",
+            image_path
+        )));
+    }
 
-        let mut second_request = client.post("https://api.shapes.inc/v1/chat/completions");
-
-        if let Some(user_auth_token) = user_auth_token {
-            let app_id = Config::load()?
-                .app_id
-                .ok_or_else(|| YuchiError::Config("No app ID set for user auth token.".to_string()))?;
-            second_request = second_request
-                .header("X-App-ID", app_id)
-                .header("X-User-Auth", user_auth_token);
-        } else if let Some(api_key) = api_key {
-            second_request = second_request
-                .header("X-User-ID", user_id)
-                .header("X-Channel-ID", channel_id)
-                .header("Authorization", format!("Bearer {}", api_key));
+    let image_data = fs::read(path).map_err(|e| {
+        YuchiError::Image(format!("Failed to read image file '{}': {}", image_path, e))
+    })?;
+    let base64_image = BASE64.encode(&image_data);
+
+    // Guess MIME type based on extension (PNG or JPEG)
+    let mime_type = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        _ => {
+            return Err(YuchiError::Image(format!(
+                "Unsupported image format for '{}'. Use PNG or JPEG.",
+                image_path
+            )))
         }
+    };
 
-        second_request = second_request.json(&json!({
-            "model": model,
-            "messages": messages,
-            "tool_choice": "none"
-        }));
+    let image_url = format!("data:{};base64,{}", mime_type, base64_image);
 
-        pb.set_message("Querying ShapesAI..."); // Restart progress bar
-        let second_res = second_request.send().map_err(|e| {
-            YuchiError::Api(format!("Failed to send second request to ShapesAI API: {}", e))
-        })?;
+    Ok(json!({
+        "role": "user",
+        "content": [
+            { "type": "text", "text": adjusted_prompt },
+            { "type": "image_url", "image_url": { "url": image_url } }
+        ]
+    }))
+}
 
-        pb.finish_and_clear();
+/// Carries on the conversation in `messages`, running the tools the shape calls, until it answers.
+/// Everything up to a failed request stays in `messages`, so calling again (say, after logging in
+/// again) sends only that request once more and doesn't run any tool twice.
+pub fn ask_shapesai(
+    client: &Client,
+    messages: &mut Vec<Value>,
+    auth: &Auth,
+    model: &str,
+    tool_settings: &ToolSettings,
+    pb: Option<&indicatif::ProgressBar>,
+) -> Result<Reply, YuchiError> {
+    let mut latency = Latency::default();
+    // Each answer handled so far added an assistant message, or a `fallback` tool result.
+    let rounds_done = messages
+        .iter()
+        .filter(|message| message["role"] == "assistant" || message["tool_call_id"] == "fallback")
+        .count();
+
+    let pb = pb.cloned().unwrap_or_else(display_progress);
+
+    for round in rounds_done..=MAX_TOOL_ROUNDS {
+        // Don't offer tools the policy would refuse anyway, nor once the rounds are used up.
+        let body = if tool_settings.policy == ToolPolicy::Ask && round < MAX_TOOL_ROUNDS {
+            json!({
+                "model": model,
+                "messages": messages,
+                "tools": tools::schemas(tool_settings),
+                "tool_choice": "auto"
+            })
+        } else {
+            json!({
+                "model": model,
+                "messages": messages,
+                "tool_choice": "none"
+            })
+        };
+        let request_builder = auth.apply(client.post(format!("{}/v1/chat/completions", BASE_URL))).json(&body);
 
-        if !second_res.status().is_success() {
-            let status = second_res.status();
-            let error_body = second_res
-                .text()
-                .unwrap_or_else(|_| "No response body".to_string());
-            return Err(YuchiError::Api(format!(
-                "Second API request failed with status: {}. Response: {}",
-                status, error_body
-            )));
+        pb.set_message("Querying ShapesAI...");
+        let started = Instant::now();
+        let (send_context, failure) = match round {
+            0 => ("Failed to send request to ShapesAI API", "API request failed"),
+            _ => ("Failed to send follow-up request to ShapesAI API", "Follow-up API request failed"),
+        };
+        let res = send(request_builder, send_context).inspect_err(|_| pb.finish_and_clear())?;
+
+        if !res.status().is_success() {
+            pb.finish_and_clear();
+            let message = match res.status() {
+                StatusCode::NOT_FOUND if round == 0 => "The resource couldn't be found.",
+                _ => failure,
+            };
+            return Err(error_for_response(res, Some(auth), message));
         }
 
-        let second_json: Value = second_res.json().map_err(|e| {
-            YuchiError::Api(format!("Failed to parse second API response: {}", e))
-        })?;
-        let reply = second_json
+        let json = read_json(res, "Failed to parse API response")?;
+        latency.requests.push(started.elapsed());
+        let message = json
             .get("choices")
             .and_then(|choices| choices.get(0))
-            .and_then(|choice| choice.get("message"))
+            .and_then(|choice| choice.get("message"));
+
+        let tool_calls = message
+            .and_then(|message| message.get("tool_calls"))
+            .and_then(|tool_calls| tool_calls.as_array())
+            .filter(|tool_calls| !tool_calls.is_empty());
+
+        if let Some(tool_calls) = tool_calls {
+            pb.finish_and_clear(); // Clear progress bar before tool execution
+            messages.push(json!({
+                "role": "assistant",
+                "tool_calls": tool_calls
+            }));
+
+            // A call without a name can't run, but still gets a result so the model can try again.
+            let mut ids = Vec::new();
+            let mut calls = Vec::new();
+            for tool_call in tool_calls {
+                let tool_call_id = tool_call
+                    .get("id")
+                    .and_then(|id| id.as_str())
+                    .ok_or_else(|| YuchiError::Api("Missing tool call ID".to_string()))?;
+                let name = tool_call.get("function").and_then(|f| f.get("name")).and_then(|name| name.as_str());
+                ids.push((tool_call_id, name.is_some()));
+                if let Some(name) = name {
+                    calls.push(tools::Call { name: name.to_string(), arguments: tool_arguments(tool_call) });
+                }
+            }
+
+            let tool_started = Instant::now();
+            let tool_results = tools::run_batch(&calls, tool_settings, Some(&pb))?;
+            latency.tools += tool_started.elapsed();
+            // Results go back in the order of the calls, whatever order they ran in.
+            let mut results = calls.iter().zip(tool_results);
+            for (tool_call_id, named) in ids {
+                let content = match named.then(|| results.next()).flatten() {
+                    Some((call, tool_result)) => {
+                        tool_settings.redactor.mask(&format!("the `{}` result", call.name), &tool_result)
+                    }
+                    None => "The tool call has no function name, so nothing ran. Call a tool by its name.".to_string(),
+                };
+                messages.push(json!({
+                    "role": "tool",
+                    "tool_call_id": tool_call_id,
+                    "content": content
+                }));
+            }
+            continue;
+        }
+
+        let content = message
             .and_then(|message| message.get("content"))
             .and_then(|content| content.as_str())
-            .unwrap_or("No response from tool execution.")
-            .to_string();
+            .unwrap_or("");
+
+        // Fallback for <function> tag format
+        if content.starts_with("<function>") && content.ends_with("</function>") {
+            pb.finish_and_clear(); // Clear progress bar before tool execution
+            let command = content
+                .strip_prefix("<function>")
+                .and_then(|s| s.strip_suffix("</function>"))
+                .ok_or_else(|| YuchiError::Api("Invalid function tag format".to_string()))?;
+
+            // The tag format only ever carries shell commands.
+            let tool_started = Instant::now();
+            let tool_result = match serde_json::from_str::<Value>(command) {
+                Ok(args) => tools::run("run_shell_command", &args, tool_settings, Approval::Ask, Some(&pb))?,
+                Err(e) => {
+                    let result = format!("Invalid arguments for run_shell_command: not valid JSON ({})", e);
+                    display_command_result("run_shell_command", &result);
+                    result
+                }
+            };
+            latency.tools += tool_started.elapsed();
+            messages.push(json!({
+                "role": "tool",
+                "tool_call_id": "fallback",
+                "content": tool_settings.redactor.mask("the `run_shell_command` result", &tool_result)
+            }));
+            continue;
+        }
 
-        return Ok(reply);
+        pb.finish_and_clear();
+        let text = if round > 0 && content.is_empty() { "No response from tool execution." } else { content };
+        return Ok(Reply { text: text.to_string(), latency });
     }
 
     pb.finish_and_clear();
-    Ok(content.to_string())
+    Err(YuchiError::Api(format!("The shape kept calling tools after {} rounds.", MAX_TOOL_ROUNDS)))
 }
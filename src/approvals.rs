@@ -0,0 +1,277 @@
+use crate::config::Config;
+use crate::errors::YuchiError;
+use crate::sandbox::Sandbox;
+use crate::tools::ToolSettings;
+use crate::ui::read_line;
+use crate::vault;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Commands the user always allows in one project, as program and arguments.
+#[derive(Serialize, Deserialize, Default)]
+struct Rules {
+    /// Commands allowed exactly as they are.
+    exact: Vec<Vec<String>>,
+    /// Commands allowed with any further arguments.
+    prefixes: Vec<Vec<String>>,
+}
+
+impl Rules {
+    fn allow(&self, words: &[String]) -> bool {
+        self.exact.iter().any(|rule| rule == words) || self.prefixes.iter().any(|rule| words.starts_with(rule))
+    }
+}
+
+/// What the user decided about a command.
+pub enum Decision {
+    /// Run `words`, which differ from the proposed command if the user edited it.
+    Run(Vec<String>),
+    /// Don't run it, optionally saying why.
+    Deny(Option<String>),
+}
+
+/// Rules live next to the config files, keyed by project root, so a repository can't grant them itself.
+fn path() -> Result<PathBuf, YuchiError> {
+    Ok(Config::path()?.with_file_name("approvals.json"))
+}
+
+fn load() -> Result<BTreeMap<PathBuf, Rules>, YuchiError> {
+    let path = path()?;
+    let raw = match fs::read_to_string(&path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(YuchiError::io(format!("Failed to read '{}'", path.display()), e)),
+    };
+    serde_json::from_str(&raw).map_err(|e| YuchiError::json(format!("'{}' is corrupted", path.display()), e))
+}
+
+/// Whether `words` is always allowed in the project at `root`. Rules that can't be read count as
+/// none, with a warning, so the user is asked instead.
+pub fn allowed(root: &Path, words: &[String]) -> bool {
+    match load() {
+        Ok(projects) => projects.get(root).is_some_and(|rules| rules.allow(words)),
+        Err(e) => {
+            eprintln!("{}", format!("Note: ignoring the always-allowed commands: {}", e).yellow());
+            false
+        }
+    }
+}
+
+fn remember(root: &Path, words: Vec<String>, prefix: bool) -> Result<(), YuchiError> {
+    let mut projects = load()?;
+    let rules = projects.entry(root.to_path_buf()).or_default();
+    let list = if prefix { &mut rules.prefixes } else { &mut rules.exact };
+    if !list.contains(&words) {
+        list.push(words);
+    }
+    let json = serde_json::to_string_pretty(&projects)
+        .map_err(|e| YuchiError::json("Failed to serialize the approvals", e))?;
+    vault::write_private(&path()?, json.as_bytes())
+}
+
+/// How a command is shown: its words joined back the way a shell would read them.
+pub fn label(words: &[String]) -> String {
+    shlex::try_join(words.iter().map(String::as_str)).unwrap_or_else(|_| words.join(" "))
+}
+
+/// Asks what to do with `words` until the user decides. `parse` splits an edited command the way
+/// the tool splits its own.
+pub fn ask(
+    words: Vec<String>,
+    root: &Path,
+    settings: &ToolSettings,
+    parse: fn(&str) -> Option<Vec<String>>,
+) -> Result<Decision, YuchiError> {
+    let current_dir = env::current_dir()
+        .map_err(|e| YuchiError::io("Failed to read the current directory", e))?
+        .to_string_lossy()
+        .into_owned();
+    let mut words = words;
+    loop {
+        let command = label(&words);
+        if settings.sandbox == Sandbox::Off {
+            println!("Run `{}` in {}?", command, current_dir);
+        } else {
+            println!("Run `{}` in {} ({})?", command, current_dir, settings.sandbox.describe());
+        }
+        let choice = read_line("[y]es once / [e]dit / [a]lways / always allow [p]refix / [n]o / e[x]plain: ")?;
+        match choice.to_lowercase().as_str() {
+            "y" | "yes" => return Ok(Decision::Run(words)),
+            "e" | "edit" => {
+                println!("Current: {}", command);
+                let edited = read_line("New command (empty keeps it): ")?;
+                if edited.is_empty() {
+                    continue;
+                }
+                match parse(&edited).filter(|words| !words.is_empty()) {
+                    Some(edited) => words = edited,
+                    None => println!("{}", "That command can't be parsed.".yellow()),
+                }
+            }
+            "a" | "always" => {
+                remember(root, words.clone(), false)?;
+                println!("{}", format!("`{}` is now always allowed in {}.", command, root.display()).green());
+                return Ok(Decision::Run(words));
+            }
+            "p" | "prefix" => {
+                let suggested = suggest_prefix(&words);
+                let answer = read_line(&format!("Allow commands starting with [{}]: ", label(suggested)))?;
+                let prefix = match answer.as_str() {
+                    "" => suggested.to_vec(),
+                    answer => match parse(answer).filter(|prefix| !prefix.is_empty()) {
+                        Some(prefix) => prefix,
+                        None => {
+                            println!("{}", "That prefix can't be parsed.".yellow());
+                            continue;
+                        }
+                    },
+                };
+                if !words.starts_with(&prefix) {
+                    println!("{}", format!("`{}` doesn't start with `{}`.", command, label(&prefix)).yellow());
+                    continue;
+                }
+                remember(root, prefix.clone(), true)?;
+                println!(
+                    "{}",
+                    format!("Commands starting with `{}` are now always allowed in {}.", label(&prefix), root.display())
+                        .green()
+                );
+                return Ok(Decision::Run(words));
+            }
+            "n" | "no" => {
+                let reason = read_line("Reason for the shape (optional): ")?;
+                return Ok(Decision::Deny(Some(reason).filter(|reason| !reason.is_empty())));
+            }
+            "x" | "explain" | "?" => explain(&words, root, settings),
+            _ => {}
+        }
+    }
+}
+
+/// The program and its subcommand, if the second word is one rather than an option or a path.
+fn suggest_prefix(words: &[String]) -> &[String] {
+    match words.get(1) {
+        Some(word) if word.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') && !word.starts_with('-') => {
+            &words[..2]
+        }
+        _ => &words[..1.min(words.len())],
+    }
+}
+
+/// Describes what running `words` would do: which program, what each argument refers to, and the
+/// conditions it runs under.
+fn explain(words: &[String], root: &Path, settings: &ToolSettings) {
+    let Some((program, args)) = words.split_first() else { return };
+    let resolved = find_program(program);
+    let summary = Command::new("whatis")
+        .arg("--")
+        .arg(program)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| {
+            let text = String::from_utf8_lossy(&output.stdout);
+            text.lines().next().and_then(|line| line.split_once(" - ")).map(|(_, what)| what.trim().to_string())
+        });
+
+    println!("{}", "What this command does:".bold());
+    match (&resolved, &summary) {
+        (Some(path), Some(what)) => println!("  Program: {} ({}): {}", program, path.display(), what),
+        (Some(path), None) => println!("  Program: {} ({})", program, path.display()),
+        (None, _) => println!("  Program: {} {}", program, "(not found on PATH)".yellow()),
+    }
+    for (i, arg) in args.iter().enumerate() {
+        let path = Path::new(arg);
+        let note = match path.canonicalize() {
+            Ok(full) if full.starts_with(root) => {
+                if full.is_dir() { " (directory in the project)" } else { " (file in the project)" }
+            }
+            Ok(_) => " (path outside the project)",
+            Err(_) if arg.starts_with('-') => " (option)",
+            Err(_) => "",
+        };
+        println!("  Argument {}: {}{}", i + 1, arg, note);
+    }
+    if words.iter().any(|word| word.contains(['|', '&', ';', '<', '>', '$', '*', '`', '"', '\''])) {
+        println!(
+            "  {}",
+            "There is no shell: pipes, redirects, variables, globs and quotes are passed along literally.".yellow()
+        );
+    }
+    if settings.sandbox == Sandbox::Off {
+        println!("  Runs with your permissions and network access.");
+    } else {
+        println!("  Runs in the {}.", settings.sandbox.describe());
+    }
+    let mut environment = settings.env.allow.join(", ");
+    if !settings.env.set.is_empty() {
+        let set: Vec<&str> = settings.env.set.iter().map(|(name, _)| name.as_str()).collect();
+        environment.push_str(&format!("; sets {}", set.join(", ")));
+    }
+    println!("  Sees the variables {}.", environment);
+    let limits = settings.limits;
+    let limits: Vec<String> = [
+        limits.cpu_seconds.map(|n| format!("{} CPU seconds", n)),
+        limits.memory_mb.map(|n| format!("{} MiB of memory", n)),
+        limits.file_size_mb.map(|n| format!("files up to {} MiB", n)),
+        limits.processes.map(|n| format!("{} processes of your user in all", n)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    if !limits.is_empty() {
+        println!("  Limited to {}.", limits.join(", "));
+    }
+}
+
+/// Where `program` would be found, the way the command will look it up.
+fn find_program(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        return Path::new(program).canonicalize().ok();
+    }
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path).map(|dir| dir.join(program)).find(|candidate| candidate.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(command: &str) -> Vec<String> {
+        command.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn exact_rules_match_only_the_same_command() {
+        let rules = Rules { exact: vec![words("cargo test")], prefixes: Vec::new() };
+        assert!(rules.allow(&words("cargo test")));
+        assert!(!rules.allow(&words("cargo test --release")));
+        assert!(!rules.allow(&words("cargo")));
+    }
+
+    #[test]
+    fn prefix_rules_match_whole_words() {
+        let rules = Rules { exact: Vec::new(), prefixes: vec![words("cargo test")] };
+        assert!(rules.allow(&words("cargo test")));
+        assert!(rules.allow(&words("cargo test -- --nocapture")));
+        assert!(!rules.allow(&words("cargo test-all")));
+        assert!(!rules.allow(&words("cargo build")));
+        assert!(!Rules::default().allow(&words("ls")));
+    }
+
+    #[test]
+    fn suggests_the_program_and_its_subcommand() {
+        assert_eq!(suggest_prefix(&words("cargo test --release")), words("cargo test"));
+        assert_eq!(suggest_prefix(&words("git log -5")), words("git log"));
+        assert_eq!(suggest_prefix(&words("rm -rf target")), words("rm"));
+        assert_eq!(suggest_prefix(&words("cat src/main.rs")), words("cat"));
+        assert_eq!(suggest_prefix(&words("ls")), words("ls"));
+        assert!(suggest_prefix(&[]).is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+use crate::config::Config;
+use crate::errors::YuchiError;
+use crate::session;
+use crate::vault;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Backups kept across all projects; older ones are dropped.
+const MAX_BACKUPS: usize = 50;
+
+/// A file as it was before a tool changed it, and as the tool left it.
+#[derive(Serialize, Deserialize)]
+pub struct SavedFile {
+    pub path: PathBuf,
+    /// `None` when the tool created the file.
+    pub before: Option<String>,
+    /// `None` when the tool deleted the file.
+    pub after: Option<String>,
+}
+
+/// Everything one tool call changed, so `yuchi undo` can put it back.
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+    pub created_at: u64,
+    pub tool: String,
+    pub root: PathBuf,
+    pub files: Vec<SavedFile>,
+}
+
+/// Backups live next to the config files, one JSON file each, named by creation time.
+fn dir() -> Result<PathBuf, YuchiError> {
+    Ok(Config::path()?.with_file_name("backups"))
+}
+
+/// Writes `changes` (new content, or `None` to delete) after recording a backup of what they replace.
+/// If a write fails, the files already written are restored before the error is returned.
+pub fn write_files(tool: &str, root: &Path, changes: &[(PathBuf, Option<String>)]) -> Result<(), YuchiError> {
+    let mut files = Vec::new();
+    for (path, after) in changes {
+        let before = if path.exists() {
+            Some(fs::read_to_string(path).map_err(|e| YuchiError::io(format!("Failed to read '{}'", path.display()), e))?)
+        } else {
+            None
+        };
+        files.push(SavedFile { path: path.clone(), before, after: after.clone() });
+    }
+    let backup = Backup { created_at: session::now(), tool: tool.to_string(), root: root.to_path_buf(), files };
+    let backup_path = save(&backup)?;
+
+    for (done, file) in backup.files.iter().enumerate() {
+        if let Err(e) = put(&file.path, file.after.as_deref()) {
+            for file in &backup.files[..done] {
+                if let Err(e) = put(&file.path, file.before.as_deref()) {
+                    log::warn!("failed to roll back '{}': {}", file.path.display(), e);
+                }
+            }
+            let _ = fs::remove_file(&backup_path);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Reverts the latest backup taken in `root`. Files changed again since are left alone
+/// unless `force` is set.
+pub fn undo(root: &Path, force: bool) -> Result<Backup, YuchiError> {
+    let (path, backup) = list()?
+        .into_iter()
+        .rev()
+        .find(|(_, backup)| backup.root == root)
+        .ok_or_else(|| YuchiError::Input(format!("Nothing to undo in '{}'.", root.display())))?;
+
+    let modified: Vec<String> = backup
+        .files
+        .iter()
+        .filter(|file| fs::read_to_string(&file.path).ok() != file.after)
+        .map(|file| file.path.display().to_string())
+        .collect();
+    if !modified.is_empty() && !force {
+        return Err(YuchiError::Input(format!(
+            "These files changed after the {} that `yuchi undo` would revert: {}. Use --force to restore them anyway.",
+            backup.tool,
+            modified.join(", ")
+        )));
+    }
+
+    for file in backup.files.iter().rev() {
+        put(&file.path, file.before.as_deref())?;
+    }
+    fs::remove_file(&path).map_err(|e| YuchiError::io(format!("Failed to remove '{}'", path.display()), e))?;
+    Ok(backup)
+}
+
+/// Writes `content` through a temporary file and a rename, so readers never see half a file.
+/// `None` removes the file.
+fn put(path: &Path, content: Option<&str>) -> Result<(), YuchiError> {
+    let Some(content) = content else {
+        return match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(YuchiError::io(format!("Failed to delete '{}'", path.display()), e))
+            }
+            _ => Ok(()),
+        };
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| YuchiError::io(format!("Failed to create '{}'", parent.display()), e))?;
+    }
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let temp = path.with_file_name(format!(".{}.yuchi-tmp", name));
+    fs::write(&temp, content).map_err(|e| YuchiError::io(format!("Failed to write '{}'", temp.display()), e))?;
+    if let Ok(metadata) = fs::metadata(path) {
+        let _ = fs::set_permissions(&temp, metadata.permissions());
+    }
+    fs::rename(&temp, path).map_err(|e| {
+        let _ = fs::remove_file(&temp);
+        YuchiError::io(format!("Failed to write '{}'", path.display()), e)
+    })
+}
+
+fn save(backup: &Backup) -> Result<PathBuf, YuchiError> {
+    let dir = dir()?;
+    let stamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    let path = dir.join(format!("{}.json", stamp));
+    let json = serde_json::to_string(backup).map_err(|e| YuchiError::json("Failed to serialize the backup", e))?;
+    // Backups hold file contents, which may be private.
+    vault::write_private(&path, json.as_bytes())?;
+
+    let backups = list()?;
+    for (old, _) in backups.iter().take(backups.len().saturating_sub(MAX_BACKUPS)) {
+        let _ = fs::remove_file(old);
+    }
+    Ok(path)
+}
+
+/// All backups, oldest first. One that can't be read is skipped with a warning, so it doesn't
+/// stand in the way of later writes and undos.
+fn list() -> Result<Vec<(PathBuf, Backup)>, YuchiError> {
+    let dir = dir()?;
+    let Ok(entries) = fs::read_dir(&dir) else { return Ok(Vec::new()) };
+    let mut backups: Vec<(u128, PathBuf)> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter_map(|path| {
+            let stamp = path.file_stem()?.to_str()?.parse().ok()?;
+            Some((stamp, path))
+        })
+        .collect();
+    backups.sort();
+
+    Ok(backups
+        .into_iter()
+        .filter_map(|(_, path)| {
+            let backup = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|raw| serde_json::from_str(&raw).map_err(|e| e.to_string()));
+            match backup {
+                Ok(backup) => Some((path, backup)),
+                Err(e) => {
+                    log::warn!("skipping backup '{}': {}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect())
+}
@@ -1,245 +1,749 @@
-use crate::api::{ask_shapesai, APP_ID};
-use crate::config::Config;
+use crate::agent;
+use crate::backup;
+use crate::api::{self, ask_shapesai, error_for_response, read_json, send, validate_credentials, Auth, APP_ID, BASE_URL};
+use crate::config::{self, Config, Origin, Setting, DEFAULT_MODEL, SETTINGS};
 use crate::errors::YuchiError;
-use crate::ui::{display_command_result, display_progress, display_response};
-use indicatif::ProgressBar;
+use crate::redact::Redactor;
+use crate::session::{self, Session, Store, DEFAULT_SESSION};
+use crate::tools::{self, ToolSettings};
+use crate::vault;
+use crate::ui::{
+    confirm, display_error, display_latency, display_progress, display_response, display_settings, display_table,
+    display_whoami,
+};
 use reqwest::blocking::Client;
-use serde_json::json;
+use serde_json::{json, Value};
 use uuid::Uuid;
 use rpassword::prompt_password;
 use std::process::Command;
 use colored::Colorize;
-use std::fs::File;
-use std::io::{self, Write};
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use regex::Regex;
 
-pub fn login() -> Result<(), YuchiError> {
-    let mut config = Config::load()?;
-    let auth_method = prompt_password("Choose authentication method (1: API key, 2: User auth token): ")
-        .map_err(|e| YuchiError::Input(e.to_string()))?
-        .trim()
-        .to_string();
+/// What questions need, loaded once per command: the effective config and one HTTP client,
+/// so every request of a conversation reuses the same pooled connection.
+pub struct Context {
+    pub config: Config,
+    pub client: Client,
+}
 
-    if auth_method == "1" {
-        let key = prompt_password("Enter API key: ")
-            .map_err(|e| YuchiError::Input(e.to_string()))?;
-        if key.trim().is_empty() {
-            return Err(YuchiError::Input("API key cannot be empty".to_string()));
-        }
+impl Context {
+    pub fn load() -> Result<Self, YuchiError> {
+        let config = Config::load()?;
+        let client = api::client(&config)?;
+        Ok(Context { config, client })
+    }
+}
 
-        if config.user_id.is_none() {
-            config.user_id = Some(Uuid::new_v4().to_string());
-            println!("{}", "Generated new user ID.".yellow());
-        }
-        if config.channel_id.is_none() {
-            config.channel_id = Some(Uuid::new_v4().to_string());
-            println!("{}", "Generated new channel ID.".yellow());
-        }
-        config.save()?;
+/// Credential sources for a login that must not prompt (CI runners, scripts).
+#[derive(Default)]
+pub struct LoginOptions {
+    /// Name of an environment variable holding the API key.
+    pub api_key_env: Option<String>,
+    /// File containing the API key.
+    pub api_key_file: Option<PathBuf>,
+    /// Read the API key from stdin.
+    pub api_key_stdin: bool,
+    /// One-time code from the authorize page, for the user auth token flow.
+    pub code: Option<String>,
+}
 
-        let user_id = config.user_id.as_ref().unwrap();
-        let channel_id = config.channel_id.as_ref().unwrap();
-        let pb = display_progress();
-        let test_response = ask_shapesai("Test", Some(&key), None, "shapesinc/ariwa", user_id, channel_id, None, Some(&pb))?;
-        pb.finish_and_clear();
+impl LoginOptions {
+    fn api_key_source_count(&self) -> usize {
+        [self.api_key_env.is_some(), self.api_key_file.is_some(), self.api_key_stdin]
+            .iter()
+            .filter(|set| **set)
+            .count()
+    }
 
-        if test_response.is_empty() {
-            return Err(YuchiError::Api("API key validation failed: No response received".to_string()));
+    /// Reads the API key from whichever non-interactive source was given, if any.
+    fn read_api_key(&self) -> Result<Option<String>, YuchiError> {
+        if let Some(var) = &self.api_key_env {
+            let key = std::env::var(var).map_err(|_| {
+                YuchiError::Input(format!("Environment variable '{}' is not set", var))
+            })?;
+            return Ok(Some(key));
         }
-
-        config.api_key = Some(key);
-        config.app_id = None;
-        config.user_auth_token = None;
-        config.save()?;
-        println!("{}", "API key validated and saved successfully!".green());
-    } else if auth_method == "2" {
-        config.app_id = Some(APP_ID.to_string());
-        config.save()?;
-
-        if config.user_id.is_none() {
-            config.user_id = Some(Uuid::new_v4().to_string());
-            println!("{}", "Generated new user ID.".yellow());
+        if let Some(path) = &self.api_key_file {
+            let key = fs::read_to_string(path).map_err(|e| {
+                YuchiError::io(format!("Failed to read API key file '{}'", path.display()), e)
+            })?;
+            return Ok(Some(key));
         }
-        if config.channel_id.is_none() {
-            config.channel_id = Some(Uuid::new_v4().to_string());
-            println!("{}", "Generated new channel ID.".yellow());
+        if self.api_key_stdin {
+            let mut key = String::new();
+            io::stdin()
+                .read_to_string(&mut key)
+                .map_err(|e| YuchiError::io("Failed to read API key from stdin", e))?;
+            return Ok(Some(key));
         }
-        config.save()?;
+        Ok(None)
+    }
+}
 
-        let user_id = config.user_id.as_ref().unwrap();
-        let channel_id = config.channel_id.as_ref().unwrap();
+pub fn login(options: &LoginOptions) -> Result<(), YuchiError> {
+    if options.api_key_source_count() > 1 {
+        return Err(YuchiError::Input(
+            "Use only one of --api-key-env, --api-key-file and --api-key-stdin.".to_string(),
+        ));
+    }
+    if options.api_key_source_count() > 0 && options.code.is_some() {
+        return Err(YuchiError::Input(
+            "--code is for the user auth token flow and cannot be combined with an API key source.".to_string(),
+        ));
+    }
 
-        println!("{}", "Click on the link to authorize the application:".yellow());
-        println!("{}", format!("https://shapes.inc/authorize?app_id={}", APP_ID).as_str().blue());
-        println!("\nAfter logging in to ShapesAI and approving the authorization request,");
-        println!("you will be given a one-time code. Copy and paste that code here.");
+    let mut config = Config::load_global()?;
+    if config.env_only {
+        return Err(YuchiError::Config(
+            "Credentials come from YUCHI_* environment variables; unset them to log in with a stored config.".to_string(),
+        ));
+    }
+    let effective = Config::load()?;
+    let client = api::client(&effective)?;
 
-        let code = prompt_password("Enter the one-time code: ")
-            .map_err(|e| YuchiError::Input(e.to_string()))?;
-        if code.trim().is_empty() {
-            return Err(YuchiError::Input("One-time code cannot be empty".to_string()));
-        }
+    if let Some(key) = options.read_api_key()? {
+        return login_with_api_key(&client, &mut config, key.trim());
+    }
+    let app_id = effective.app_id();
+    if let Some(code) = &options.code {
+        return login_with_user_token(&client, &mut config, &app_id, Some(code));
+    }
 
-        let pb = display_progress();
-        let client = Client::new();
-        let response = client
-            .post("https://api.shapes.inc/auth/nonce")
-            .json(&json!({
-                "app_id": APP_ID,
-                "code": code
-            }))
-            .send()
-            .map_err(|e| YuchiError::Api(format!("Failed to exchange one-time code: {}", e)))?;
-
-        if !response.status().is_success() {
-            pb.finish_and_clear();
-            let status = response.status();
-            let error_body = response.text().unwrap_or_else(|_| "No response body".to_string());
-            return Err(YuchiError::Api(format!("Failed to exchange one-time code with status: {}. Response: {}", status, error_body)));
+    if !io::stdin().is_terminal() {
+        return Err(YuchiError::Input(
+            "No terminal available. Pass --api-key-env, --api-key-file, --api-key-stdin or --code to log in non-interactively.".to_string(),
+        ));
+    }
+
+    print!("Choose authentication method (1: API key, 2: User auth token): ");
+    io::stdout().flush().map_err(|e| YuchiError::io("Failed to flush stdout", e))?;
+    let mut auth_method = String::new();
+    io::stdin()
+        .read_line(&mut auth_method)
+        .map_err(YuchiError::prompt)?;
+
+    match auth_method.trim() {
+        "1" => {
+            let key = prompt_password("Enter API key: ")
+                .map_err(YuchiError::prompt)?;
+            login_with_api_key(&client, &mut config, key.trim())
         }
+        "2" => login_with_user_token(&client, &mut config, &app_id, None),
+        _ => Err(YuchiError::Input("Invalid authentication method. Choose 1 for API key or 2 for user auth token.".to_string())),
+    }
+}
 
-        let response_json = response.json::<serde_json::Value>()
-            .map_err(|e| YuchiError::Api(format!("Failed to parse auth token response: {}", e)))?;
-        let user_auth_token = response_json
-            .get("auth_token")
-            .and_then(|t| t.as_str())
-            .ok_or_else(|| YuchiError::Api("Missing auth_token in response".to_string()))?;
+fn ensure_ids(config: &mut Config) {
+    if config.user_id.is_none() {
+        config.user_id = Some(Uuid::new_v4().to_string());
+        println!("{}", "Generated new user ID.".yellow());
+    }
+    if config.channel_id.is_none() {
+        config.channel_id = Some(Uuid::new_v4().to_string());
+        println!("{}", "Generated new channel ID.".yellow());
+    }
+}
 
-        let test_response = ask_shapesai("Test", None, Some(user_auth_token), "shapesinc/ariwa", user_id, channel_id, None, Some(&pb))?;
-        pb.finish_and_clear();
+fn login_with_api_key(client: &Client, config: &mut Config, key: &str) -> Result<(), YuchiError> {
+    if key.is_empty() {
+        return Err(YuchiError::Input("API key cannot be empty".to_string()));
+    }
+
+    let pb = display_progress();
+    pb.set_message("Validating API key...");
+    let validation = validate_credentials(
+        client,
+        &Auth::ApiKey { api_key: key, user_id: "", channel_id: "" },
+        DEFAULT_MODEL,
+    );
+    pb.finish_and_clear();
+    validation?;
+
+    ensure_ids(config);
+    config.api_key = Some(key.to_string());
+    config.user_auth_token = None;
+    config.save()?;
+    println!("{}", "API key validated and saved successfully!".green());
+    Ok(())
+}
 
-        if test_response.is_empty() {
-            return Err(YuchiError::Api("User auth token validation failed: No response received".to_string()));
+fn login_with_user_token(
+    client: &Client,
+    config: &mut Config,
+    app_id: &str,
+    code: Option<&str>,
+) -> Result<(), YuchiError> {
+    let code = match code {
+        Some(code) => code.to_string(),
+        None => {
+            println!("{}", "Click on the link to authorize the application:".yellow());
+            println!("{}", format!("https://shapes.inc/authorize?app_id={}", app_id).as_str().blue());
+            println!("\nAfter logging in to ShapesAI and approving the authorization request,");
+            println!("you will be given a one-time code. Copy and paste that code here.");
+
+            prompt_password("Enter the one-time code: ")
+                .map_err(YuchiError::prompt)?
         }
+    };
+    let code = code.trim();
+    if code.is_empty() {
+        return Err(YuchiError::Input("One-time code cannot be empty".to_string()));
+    }
 
-        config.user_auth_token = Some(user_auth_token.to_string());
-        config.api_key = None;
-        config.save()?;
-        println!("{}", "User auth token validated and saved successfully!".green());
-    } else {
-        return Err(YuchiError::Input("Invalid authentication method. Choose 1 for API key or 2 for user auth token.".to_string()));
+    let pb = display_progress();
+    let response = send(
+        client.post(format!("{}/auth/nonce", BASE_URL)).json(&json!({
+            "app_id": app_id,
+            "code": code
+        })),
+        "Failed to exchange one-time code",
+    )
+    .inspect_err(|_| pb.finish_and_clear())?;
+
+    if !response.status().is_success() {
+        pb.finish_and_clear();
+        return Err(error_for_response(response, None, "Failed to exchange one-time code"));
     }
 
+    let response_json = read_json(response, "Failed to parse auth token response")?;
+    let user_auth_token = response_json
+        .get("auth_token")
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| YuchiError::Api("Missing auth_token in response".to_string()))?;
+
+    pb.set_message("Validating user auth token...");
+    let validation = validate_credentials(
+        client,
+        &Auth::UserToken { token: user_auth_token, app_id, channel_id: None },
+        DEFAULT_MODEL,
+    );
+    pb.finish_and_clear();
+    validation?;
+
+    ensure_ids(config);
+    config.app_id = (app_id != APP_ID).then(|| app_id.to_string());
+    config.user_auth_token = Some(user_auth_token.to_string());
+    config.api_key = None;
+    config.save()?;
+    println!("{}", "User auth token validated and saved successfully!".green());
     Ok(())
 }
 
 pub fn set_shape(username: &str) -> Result<(), YuchiError> {
-    let config = Config::load()?;
-    let user_id = config.user_id
-        .ok_or_else(|| YuchiError::Config("No user ID set. Run `yuchi --login` first.".to_string()))?;
-    let channel_id = config.channel_id
-        .ok_or_else(|| YuchiError::Config("No channel ID set. Run `yuchi --login` first.".to_string()))?;
+    let effective = Config::load()?;
+    if effective.env_only {
+        return Err(YuchiError::Config(
+            "Environment-only mode is active; set YUCHI_SHAPE instead of saving a shape.".to_string(),
+        ));
+    }
+    let app_id = effective.app_id();
+    let auth = effective.auth(&app_id)?;
+    let client = api::client(&effective)?;
 
     let model = format!("shapesinc/{}", username);
     let pb = display_progress();
-    let test_response = if let Some(user_auth_token) = &config.user_auth_token {
-        ask_shapesai("Test", None, Some(user_auth_token), &model, &user_id, &channel_id, None, Some(&pb))?
-    } else if let Some(api_key) = &config.api_key {
-        ask_shapesai("Test", Some(api_key), None, &model, &user_id, &channel_id, None, Some(&pb))?
-    } else {
-        return Err(YuchiError::Config("No API key or user auth token set. Run `yuchi --login` first.".to_string()));
-    };
+    pb.set_message("Checking shape...");
+    let validation = validate_credentials(&client, &auth, &model).map_err(|e| attribute_rejection(&effective, e));
     pb.finish_and_clear();
+    validation?;
 
-    if test_response.is_empty() {
-        return Err(YuchiError::Api("Username validation failed: No response received.".to_string()));
-    }
-
-    let mut config = Config::load()?;
-    config.username = Some(username.to_string());
+    let mut config = Config::load_global()?;
+    config.shape = Some(username.to_string());
     config.save()?;
     println!("{}", format!("Username '{}' validated and saved successfully! Using model: {}", username, model).as_str().green());
+    warn_if_overridden(&effective, "shape");
+    Ok(())
+}
+
+/// Points out when a value just saved to the config file is shadowed by a higher layer.
+fn warn_if_overridden(effective: &Config, key: &str) {
+    if let origin @ (Origin::Project(_) | Origin::Env(_) | Origin::Flag(_)) = effective.origin(key) {
+        println!("{}", format!("Note: '{}' is currently overridden by {}.", key, origin).yellow());
+    }
+}
+
+/// Prints the active auth method, shape and IDs, and whether ShapesAI accepts the credentials.
+pub fn whoami() -> Result<(), YuchiError> {
+    let config = Config::load()?;
+
+    let auth_method = if config.user_auth_token.is_some() {
+        "User auth token"
+    } else if config.api_key.is_some() {
+        "API key"
+    } else {
+        return Err(YuchiError::Config("Not logged in. Run `yuchi login` first.".to_string()));
+    };
+    let source = if config.env_only {
+        "environment (YUCHI_*)".to_string()
+    } else {
+        Config::path()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|_| "config file".to_string())
+    };
+    let model = config.model();
+    let app_id = config.app_id();
+
+    let pb = display_progress();
+    pb.set_message("Checking credentials...");
+    let status = match api::client(&config)
+        .and_then(|client| validate_credentials(&client, &config.auth(&app_id)?, &model))
+    {
+        Ok(()) => "valid".green().to_string(),
+        Err(e) => e.to_string().red().to_string(),
+    };
+    pb.finish_and_clear();
+
+    let unset = || "(not set)".to_string();
+    display_whoami(&[
+        ("Profile", config::profile().to_string()),
+        ("Auth method", auth_method.to_string()),
+        ("Source", source),
+        ("App ID", app_id),
+        ("Shape", config.shape.clone().unwrap_or_else(unset)),
+        ("Model", model),
+        ("Session", config.session.clone().unwrap_or_else(|| "(none, environment-only)".to_string())),
+        ("User ID", config.user_id.clone().unwrap_or_else(unset)),
+        ("Channel ID", config.channel_id.clone().unwrap_or_else(unset)),
+        ("Status", status),
+    ]);
     Ok(())
 }
 
 pub fn logout() -> Result<(), YuchiError> {
+    if config::env_only() {
+        return Err(YuchiError::Config(
+            "Credentials come from YUCHI_* environment variables; there is no stored config to clear.".to_string(),
+        ));
+    }
     let config = Config::default();
     config.save()?;
-    println!("{}", "API key, app ID, auth token, username, user ID, and channel ID cleared!".green());
+    agent::stop();
+    println!("{}", "API key, app ID, auth token, shape, user ID, and channel ID cleared!".green());
     Ok(())
 }
 
-pub fn ask(question: &str, model_override: Option<&str>, image_path: Option<&str>) -> Result<String, YuchiError> {
-    let config = Config::load()?;
-    let user_id = config.user_id
-        .ok_or_else(|| YuchiError::Config("No user ID set. Run `yuchi --login` first.".to_string()))?;
-    let channel_id = config.channel_id
-        .ok_or_else(|| YuchiError::Config("No channel ID set. Run `yuchi --login` first.".to_string()))?;
-
-    let default_model = config
-        .username
-        .as_ref()
-        .map(|u| format!("shapesinc/{}", u))
-        .unwrap_or_else(|| "shapesinc/ariwa".to_string());
-    let model = model_override.unwrap_or(&default_model);
+pub fn unlock(ttl_secs: u64) -> Result<(), YuchiError> {
+    vault::unlock(ttl_secs)?;
+    println!("{}", format!("Vault unlocked for {} seconds.", ttl_secs).green());
+    Ok(())
+}
 
-    let pb = display_progress();
-    let reply = if let Some(user_auth_token) = &config.user_auth_token {
-        ask_shapesai(question, None, Some(user_auth_token), &model, &user_id, &channel_id, image_path, Some(&pb))?
-    } else if let Some(api_key) = &config.api_key {
-        ask_shapesai(question, Some(api_key), None, &model, &user_id, &channel_id, image_path, Some(&pb))?
+pub fn lock() {
+    if agent::stop() {
+        println!("{}", "Vault locked.".green());
     } else {
-        return Err(YuchiError::Config("No API key or user auth token set. Run `yuchi --login` first.".to_string()));
+        println!("{}", "Vault agent is not running.".yellow());
+    }
+}
+
+pub fn undo(force: bool) -> Result<(), YuchiError> {
+    let root = tools::project_root()?;
+    let backup = backup::undo(&root, force)?;
+    for file in &backup.files {
+        let path = file.path.strip_prefix(&root).unwrap_or(&file.path).display();
+        let action = match (&file.before, &file.after) {
+            (None, _) => "removed",
+            (_, None) => "restored (was deleted)",
+            _ => "restored",
+        };
+        println!("{} {}", path, action);
+    }
+    println!("{}", format!("Reverted {} ({}).", backup.tool, ago(session::now().saturating_sub(backup.created_at))).green());
+    Ok(())
+}
+
+pub fn ask(
+    context: &mut Context,
+    question: &str,
+    model_override: Option<&str>,
+    image_path: Option<&str>,
+) -> Result<String, YuchiError> {
+    let default_model = context.config.model();
+    let model = model_override.unwrap_or(&default_model);
+    let app_id = context.config.app_id();
+
+    let tool_settings = context.config.tool_settings()?;
+    let prompt = with_context(&context.config, &tool_settings.redactor, question)?;
+    let mut messages = vec![api::question(&prompt, image_path)?];
+
+    let reply = match ask_once(context, &mut messages, model, &app_id) {
+        // Only the rejected request is sent again: tools that already ran for this question don't run twice.
+        Err(e @ YuchiError::Auth(_)) if io::stdin().is_terminal() && !context.config.env_only => {
+            display_error(&e);
+            if !confirm(&format!("Log in again for profile '{}' now?", config::profile()))? {
+                return Err(e);
+            }
+            reauthenticate(&context.client, context.config.user_auth_token.is_some(), &app_id)?;
+            context.config = Config::load()?;
+            ask_once(context, &mut messages, model, &app_id)?
+        }
+        result => result?,
     };
-    pb.finish_and_clear();
 
     display_response(question, &reply);
+    // Shape commands like `!reset` aren't part of the conversation worth summarizing.
+    if !question.starts_with('!') {
+        session::record(&context.config, question, &reply)?;
+    }
     Ok(reply)
 }
 
-pub fn run_tool(command: &str, pb: Option<&ProgressBar>) -> Result<String, YuchiError> {
-    let current_dir = std::env::current_dir()
-        .map_err(|e| YuchiError::Tool(e.to_string()))?
-        .to_string_lossy()
-        .into_owned();
+/// Interactive conversation: every line is sent as a question until `exit`, `quit` or end of input.
+pub fn chat(model_override: Option<&str>) -> Result<(), YuchiError> {
+    let mut context = Context::load()?;
+    println!("{}", "Type `exit` or press Ctrl-D to leave.".yellow());
+    loop {
+        print!("{}", "You: ".bold());
+        io::stdout().flush().map_err(|e| YuchiError::io("Failed to flush stdout", e))?;
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line).map_err(YuchiError::prompt)? == 0 {
+            println!();
+            return Ok(());
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "exit" || line == "quit" {
+            return Ok(());
+        }
+
+        match ask(&mut context, line, model_override, None) {
+            Ok(_) => {}
+            // A failed turn shouldn't end the conversation; missing config, auth or a cancel should.
+            Err(e @ (YuchiError::Network { .. }
+            | YuchiError::Http { .. }
+            | YuchiError::RateLimited { .. }
+            | YuchiError::Api(_)
+            | YuchiError::Json { .. }
+            | YuchiError::Tool(_))) => display_error(&e),
+            Err(e) => return Err(e),
+        }
+    }
+}
 
-    // Print prompt with newline and flush to ensure visibility
-    println!("Run `{}` in {}? (y/n): ", command, current_dir);
-    io::stdout().flush().map_err(|e| YuchiError::Input(format!("Failed to flush stdout: {}", e)))?;
+pub fn session_list() -> Result<(), YuchiError> {
+    let config = Config::load()?;
+    let default_channel = Config::load_global()?.channel_id.unwrap_or_else(|| "(not set)".to_string());
+    let store = Store::load()?;
+    let mut names: Vec<&str> = store.sessions.keys().map(String::as_str).collect();
+    if !store.sessions.contains_key(DEFAULT_SESSION) {
+        names.insert(0, DEFAULT_SESSION);
+    }
 
-    // Read a single line from stdin
-    let mut input = String::new();
-    io::stdin()
-        .read_line(&mut input)
-        .map_err(|e| YuchiError::Input(format!("Failed to read input: {}", e)))?;
+    let now = session::now();
+    let rows: Vec<Vec<String>> = names
+        .into_iter()
+        .map(|name| {
+            let stored = store.sessions.get(name);
+            let marker = if config.session.as_deref() == Some(name) { "* " } else { "  " };
+            let channel = match (name, stored) {
+                (DEFAULT_SESSION, _) => default_channel.clone(),
+                (_, Some(s)) => s.channel_id.clone(),
+                _ => String::new(),
+            };
+            vec![
+                format!("{}{}", marker, name),
+                channel,
+                stored
+                    .and_then(|s| s.project_root.as_ref())
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_default(),
+                stored.map(|s| s.transcript.len()).unwrap_or(0).to_string(),
+                stored.map(|s| ago(now.saturating_sub(s.last_used))).unwrap_or_else(|| "never".to_string()),
+            ]
+        })
+        .collect();
+    display_table(&["Session", "Channel", "Project", "Exchanges", "Last used"], &rows);
+    Ok(())
+}
 
-    let confirmation = input.trim().to_lowercase();
-    if confirmation != "y" {
-        let result = "Command execution cancelled by user.".to_string();
-        display_command_result(command, &result);
-        return Ok(result);
+pub fn session_rename(old: &str, new: &str) -> Result<(), YuchiError> {
+    session::check_name(new)?;
+    if old == DEFAULT_SESSION || new == DEFAULT_SESSION {
+        return Err(YuchiError::Input(format!(
+            "The '{}' session belongs to the profile and can't be renamed.",
+            DEFAULT_SESSION
+        )));
     }
+    let mut store = Store::load()?;
+    if store.sessions.contains_key(new) {
+        return Err(YuchiError::Input(format!("A session named '{}' already exists.", new)));
+    }
+    let session = store.get(old)?.clone();
+    store.sessions.remove(old);
+    store.sessions.insert(new.to_string(), session);
+    store.save()?;
+    println!("{}", format!("Renamed session '{}' to '{}'.", old, new).green());
+    Ok(())
+}
 
-    let pb = pb.map(|p| p.clone()).unwrap_or_else(|| display_progress());
+/// Starts `new` in a fresh channel, opening it with a recap of `from` (the active session by default).
+pub fn session_fork(new: &str, from: Option<&str>) -> Result<(), YuchiError> {
+    session::check_name(new)?;
+    let mut config = Config::load()?;
+    if config.env_only {
+        return Err(YuchiError::Config(
+            "Sessions need a stored config; they can't be forked in environment-only mode.".to_string(),
+        ));
+    }
+    let mut store = Store::load()?;
+    if new == DEFAULT_SESSION || store.sessions.contains_key(new) {
+        return Err(YuchiError::Input(format!("A session named '{}' already exists.", new)));
+    }
+    let from = from
+        .map(str::to_string)
+        .or_else(|| config.session.clone())
+        .unwrap_or_else(|| DEFAULT_SESSION.to_string());
+    let transcript = match store.sessions.get(&from) {
+        None if from == DEFAULT_SESSION => Vec::new(),
+        _ => store.get(&from)?.transcript.clone(),
+    };
 
-    let parts: Vec<&str> = command.split_whitespace().collect();
-    if parts.is_empty() {
-        return Err(YuchiError::Tool("Empty command".to_string()));
+    let mut forked = Session::new(Uuid::new_v4().to_string(), None);
+    if !transcript.is_empty() {
+        config.channel_id = Some(forked.channel_id.clone());
+        let app_id = config.app_id();
+        let auth = config.auth(&app_id)?;
+        let pb = display_progress();
+        pb.set_message("Seeding the new session...");
+        let client = api::client(&config)?;
+        let summary = config.redactor()?.mask("the session summary", &session::summary(&transcript));
+        let mut messages = vec![api::question(&summary, None)?];
+        let seeded = ask_shapesai(&client, &mut messages, &auth, &config.model(), &ToolSettings::deny(), Some(&pb))
+            .map_err(|e| attribute_rejection(&config, e));
+        pb.finish_and_clear();
+        seeded?;
     }
-    let (program, args) = (parts[0], &parts[1..]);
+    forked.transcript = transcript;
+    store.sessions.insert(new.to_string(), forked);
+    store.save()?;
+    println!(
+        "{}",
+        format!("Forked session '{}' into '{}'. Use it with `yuchi --session {}`.", from, new, new).green()
+    );
+    Ok(())
+}
 
-    let output = Command::new(program)
-        .args(args)
-        .output()
-        .map_err(|e| YuchiError::Tool(format!("Failed to execute `{}`: {}", command, e)))?;
+pub fn session_delete(name: &str) -> Result<(), YuchiError> {
+    if name == DEFAULT_SESSION {
+        return Err(YuchiError::Input(format!(
+            "The '{}' session belongs to the profile and can't be deleted.",
+            DEFAULT_SESSION
+        )));
+    }
+    let mut store = Store::load()?;
+    store.get(name)?;
+    store.sessions.remove(name);
+    store.save()?;
+    println!("{}", format!("Deleted session '{}'.", name).green());
+    Ok(())
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
-    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+/// Rough age for listings, e.g. `5m ago`.
+fn ago(secs: u64) -> String {
+    match secs {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
 
-    let result = if output.status.success() {
-        format!("`{}` succeeded:\n{}", command, stdout)
-    } else {
-        format!("`{}` failed:\n{}", command, stderr)
+pub fn config_path() -> Result<(), YuchiError> {
+    println!("{}", Config::path()?.display());
+    Ok(())
+}
+
+pub fn config_get(key: &str, reveal: bool) -> Result<(), YuchiError> {
+    let setting = Setting::find(key)?;
+    let config = Config::load()?;
+    match config.get(setting.key) {
+        Some(value) => println!("{}", setting.display(&value, reveal)),
+        None => println!("{}", "(not set)".dimmed()),
+    }
+    Ok(())
+}
+
+pub fn config_set(key: &str, value: &str) -> Result<(), YuchiError> {
+    let mut config = Config::load_global()?;
+    config.set(key, Some(value.trim().to_string()))?;
+    config.save()?;
+    println!("{}", format!("Set '{}'.", key).green());
+    warn_if_overridden(&Config::load()?, key);
+    Ok(())
+}
+
+pub fn config_unset(key: &str) -> Result<(), YuchiError> {
+    let mut config = Config::load_global()?;
+    config.set(key, None)?;
+    config.save()?;
+    println!("{}", format!("Unset '{}'.", key).green());
+    warn_if_overridden(&Config::load()?, key);
+    Ok(())
+}
+
+/// Prints every effective setting, with its description or, given `show_origin`, the layer it came from.
+pub fn config_list(reveal: bool, show_origin: bool) -> Result<(), YuchiError> {
+    let config = Config::load()?;
+    let rows: Vec<(&str, String, String)> = SETTINGS
+        .iter()
+        .map(|setting| {
+            let value = config
+                .get(setting.key)
+                .map(|v| setting.display(&v, reveal))
+                .unwrap_or_else(|| "(not set)".to_string());
+            let detail = if show_origin {
+                config.origin(setting.key).to_string()
+            } else {
+                setting.description.to_string()
+            };
+            (setting.key, value, detail)
+        })
+        .collect();
+    display_settings(if show_origin { "Origin" } else { "Description" }, &rows);
+    Ok(())
+}
+
+/// Opens the config file in `$VISUAL`/`$EDITOR` and restores the previous contents if the result doesn't validate.
+pub fn config_edit() -> Result<(), YuchiError> {
+    let config = Config::load_global()?;
+    let path = Config::path()?;
+    if !path.exists() {
+        config.save()?;
+    }
+    let backup = fs::read(&path)
+        .map_err(|e| YuchiError::io(format!("Failed to read config '{}'", path.display()), e))?;
+
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // Editors are often configured with arguments, e.g. `code --wait`.
+    let mut editor_parts = editor.split_whitespace();
+    let program = editor_parts.next().unwrap_or("vi");
+    let status = Command::new(program)
+        .args(editor_parts)
+        .arg(&path)
+        .status()
+        .map_err(|e| YuchiError::io(format!("Failed to start editor '{}'", editor), e))?;
+    if !status.success() {
+        return Err(YuchiError::Cancelled(format!("Editor '{}' exited with {}.", editor, status)));
+    }
+
+    let problems = match Config::read(&path) {
+        Ok((edited, _)) => edited
+            .invalid_settings()
+            .into_iter()
+            .map(|(_, e)| e.to_string())
+            .collect::<Vec<_>>(),
+        Err(e) => vec![e.to_string()],
     };
+    if !problems.is_empty() {
+        vault::write_private(&path, &backup)?;
+        return Err(YuchiError::Config(format!(
+            "The edited config is invalid and was restored: {}",
+            problems.join("; ")
+        )));
+    }
+    vault::set_private(&path)?;
+    println!("{}", "Config saved.".green());
+    Ok(())
+}
 
-    display_command_result(command, &result);
+pub fn config_validate() -> Result<(), YuchiError> {
+    let config = Config::load()?;
+    let problems = config.invalid_settings();
+    if problems.is_empty() {
+        println!("{}", "Config is valid.".green());
+        return Ok(());
+    }
+    for (_, e) in &problems {
+        eprintln!("{}", e.to_string().red());
+    }
+    Err(YuchiError::Config(format!("{} invalid setting(s).", problems.len())))
+}
+
+fn ask_once(context: &Context, messages: &mut Vec<Value>, model: &str, app_id: &str) -> Result<String, YuchiError> {
+    let config = &context.config;
+    let auth = config.auth(app_id)?;
+    let tool_settings = config.tool_settings()?;
+    let started = Instant::now();
+    let pb = display_progress();
+    let reply = ask_shapesai(&context.client, messages, &auth, model, &tool_settings, Some(&pb))
+        .map_err(|e| attribute_rejection(config, e));
     pb.finish_and_clear();
+    let reply = reply?;
+    if log::log_enabled!(log::Level::Info) {
+        display_latency(&reply.latency, started.elapsed());
+    }
+    Ok(reply.text)
+}
+
+/// Largest part of a context file sent with a question, in bytes.
+const MAX_CONTEXT_BYTES: usize = 64 * 1024;
+
+/// Prefixes `question` with the configured context files, with secrets in both masked. Shape
+/// commands like `!reset` go without the context, but their arguments are masked too.
+fn with_context(config: &Config, redactor: &Redactor, question: &str) -> Result<String, YuchiError> {
+    let paths = config.context_paths()?;
+    let question = redactor.mask("the question", question);
+    if question.starts_with('!') || paths.is_empty() {
+        return Ok(question);
+    }
 
-    Ok(result)
+    let mut prompt = String::new();
+    for path in paths {
+        let bytes = fs::read(&path)
+            .map_err(|e| YuchiError::io(format!("Failed to read context file '{}'", path.display()), e))?;
+        let text = String::from_utf8_lossy(&bytes[..bytes.len().min(MAX_CONTEXT_BYTES)]);
+        let text = redactor.mask(&format!("`{}`", path.display()), &text);
+        let truncated = if bytes.len() > MAX_CONTEXT_BYTES { "\n[truncated]" } else { "" };
+        prompt.push_str(&format!("Context from `{}`:\n```\n{}{}\n```\n\n", path.display(), text, truncated));
+    }
+    prompt.push_str(&question);
+    Ok(prompt)
+}
+
+/// Names the credentials a rejection applies to: the active profile, or the environment.
+fn attribute_rejection(config: &Config, e: YuchiError) -> YuchiError {
+    match e {
+        YuchiError::Auth(msg) if config.env_only => YuchiError::Auth(format!(
+            "{} Update YUCHI_API_KEY or YUCHI_USER_AUTH_TOKEN.",
+            msg
+        )),
+        YuchiError::Auth(msg) => YuchiError::Auth(format!(
+            "{} Profile '{}' needs to log in again: run `yuchi login{}`.",
+            msg,
+            config::profile(),
+            match config::profile() {
+                config::DEFAULT_PROFILE => String::new(),
+                other => format!(" --profile {}", other),
+            }
+        )),
+        other => other,
+    }
+}
+
+/// Re-runs the login flow matching the rejected credentials.
+fn reauthenticate(client: &Client, user_token: bool, app_id: &str) -> Result<(), YuchiError> {
+    let mut config = Config::load_global()?;
+    if user_token {
+        login_with_user_token(client, &mut config, app_id, None)
+    } else {
+        let key = prompt_password("Enter API key: ")
+            .map_err(YuchiError::prompt)?;
+        login_with_api_key(client, &mut config, key.trim())
+    }
 }
 
-pub fn download_image(response: &str) -> Result<(), YuchiError> {
+/// Where `yuchi imagine` saves generated images.
+pub const DOWNLOAD_DIR: &str = "/sdcard";
+
+pub fn download_image(context: &Context, response: &str) -> Result<(), YuchiError> {
     let re = Regex::new(r"https://files\.shapes\.inc/[^\s]+")
         .map_err(|e| YuchiError::Api(format!("Failed to compile regex: {}", e)))?;
     let url = re
@@ -247,32 +751,29 @@ pub fn download_image(response: &str) -> Result<(), YuchiError> {
         .map(|m| m.as_str())
         .ok_or_else(|| YuchiError::Api("No valid image URL found in response".to_string()))?;
 
-    let client = Client::new();
     let pb = display_progress();
     pb.set_message("Downloading image...");
 
-    let res = client
-        .get(url)
-        .send()
-        .map_err(|e| YuchiError::Api(format!("Failed to download image: {}", e)))?;
+    let res = send(context.client.get(url), "Failed to download image")
+        .inspect_err(|_| pb.finish_and_clear())?;
 
     if !res.status().is_success() {
         pb.finish_and_clear();
-        return Err(YuchiError::Api(format!("Failed to download image, status: {}", res.status())));
+        return Err(error_for_response(res, None, "Failed to download image"));
     }
 
     let bytes = res
         .bytes()
-        .map_err(|e| YuchiError::Api(format!("Failed to read image bytes: {}", e)))?;
+        .map_err(|e| YuchiError::network("Failed to read image bytes", e))?;
 
-    let filename = format!("/sdcard/yuchi_image_{}.png", Uuid::new_v4());
+    let filename = format!("{}/yuchi_image_{}.png", DOWNLOAD_DIR, Uuid::new_v4());
     let path = Path::new(&filename);
 
     let mut file = File::create(path)
-        .map_err(|e| YuchiError::Api(format!("Failed to create file '{}': {}", filename, e)))?;
+        .map_err(|e| YuchiError::io(format!("Failed to create file '{}'", filename), e))?;
 
     file.write_all(&bytes)
-        .map_err(|e| YuchiError::Api(format!("Failed to write image to '{}': {}", filename, e)))?;
+        .map_err(|e| YuchiError::io(format!("Failed to write image to '{}'", filename), e))?;
 
     pb.finish_and_clear();
     println!("{}", format!("Image saved as '{}'", filename).green());
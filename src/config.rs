@@ -1,24 +1,964 @@
+use crate::api::{Auth, APP_ID};
 use crate::errors::YuchiError;
+use crate::process::{Limits, ToolEnv};
+use crate::redact::Redactor;
+use crate::sandbox::Sandbox;
+use crate::session;
+use crate::tools::{self, ToolSettings};
+use crate::vault::{self, Secrets};
+use colored::Colorize;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Default)]
+/// Layout version written to config files; files from 0.2.0 have none and count as 0.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Model used when no shape username has been set.
+pub const DEFAULT_MODEL: &str = "shapesinc/ariwa";
+
+/// Profile used when neither `--profile` nor `YUCHI_PROFILE` is given.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Per-repository settings file, found by walking up from the current directory.
+pub const PROJECT_FILE: &str = ".yuchi.toml";
+
+static PROFILE: OnceLock<String> = OnceLock::new();
+static FLAGS: OnceLock<Vec<(&'static str, &'static str, String)>> = OnceLock::new();
+
+/// Selects the profile for this run. Only the first call has an effect.
+pub fn set_profile(name: Option<&str>) -> Result<(), YuchiError> {
+    let name = name
+        .map(str::to_string)
+        .or_else(|| env_var("YUCHI_PROFILE"))
+        .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+    if !is_valid_name(&name) {
+        return Err(YuchiError::Input(format!(
+            "Invalid profile name '{}'. Use letters, digits, '-' and '_'.",
+            name
+        )));
+    }
+    let _ = PROFILE.set(name);
+    Ok(())
+}
+
+/// Whether `name` is usable as a profile or session name: letters, digits, '-' and '_'.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Name of the active profile.
+pub fn profile() -> &'static str {
+    PROFILE.get().map(String::as_str).unwrap_or(DEFAULT_PROFILE)
+}
+
+/// Records settings given on the command line as `(key, flag, value)`; they override every other layer.
+pub fn set_flags(flags: Vec<(&'static str, &'static str, String)>) {
+    let _ = FLAGS.set(flags);
+}
+
+/// Whether credentials come from `YUCHI_API_KEY` / `YUCHI_USER_AUTH_TOKEN`, bypassing the config file.
+pub fn env_only() -> bool {
+    env_var("YUCHI_API_KEY").is_some() || env_var("YUCHI_USER_AUTH_TOKEN").is_some()
+}
+
+/// What a repository may ask yuchi to do with tool calls.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ToolPolicy {
+    /// Confirm every command with the user.
+    Ask,
+    /// Refuse every command without asking.
+    Deny,
+}
+
+/// The layer an effective setting came from.
+#[derive(Clone)]
+pub enum Origin {
+    Default,
+    Global(PathBuf),
+    Vault(PathBuf),
+    Project(PathBuf),
+    Env(&'static str),
+    Flag(&'static str),
+    Session(String),
+    Generated,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Default => write!(f, "default"),
+            Origin::Global(path) => write!(f, "global config ({})", path.display()),
+            Origin::Vault(path) => write!(f, "vault ({})", path.display()),
+            Origin::Project(path) => write!(f, "project ({})", path.display()),
+            Origin::Env(var) => write!(f, "environment ({})", var),
+            Origin::Flag(flag) => write!(f, "flag ({})", flag),
+            Origin::Session(name) => write!(f, "session '{}'", name),
+            Origin::Generated => write!(f, "generated for this run"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Config {
+    #[serde(default)]
+    pub version: u32,
+    /// Kept in the encrypted vault; only read from the file to migrate plaintext configs.
+    #[serde(default, skip_serializing)]
     pub api_key: Option<String>,
     pub app_id: Option<String>,
+    /// Kept in the encrypted vault; only read from the file to migrate plaintext configs.
+    #[serde(default, skip_serializing)]
     pub user_auth_token: Option<String>,
-    pub username: Option<String>,
+    pub shape: Option<String>,
     pub user_id: Option<String>,
     pub channel_id: Option<String>,
+    /// `ask` or `deny`; see [`ToolPolicy`].
+    pub tool_policy: Option<String>,
+    /// `auto` runs the read-only search tools without asking; `ask` confirms them like the others.
+    pub search_approval: Option<String>,
+    /// `off`, `auto`, `bwrap` or `namespaces`: where tool commands run.
+    pub sandbox: Option<String>,
+    /// `all`, `patterns` (no entropy check) or `off`: what is masked before sending.
+    pub redaction: Option<String>,
+    /// Files whose contents are sent along with every question.
+    pub context_files: Option<Vec<String>>,
+    /// `profile` (one conversation for the profile) or `project` (one per git repository).
+    pub session_mode: Option<String>,
+    /// HTTP(S) proxy for every request, e.g. `http://proxy.corp:3128`.
+    pub proxy: Option<String>,
+    /// PEM file of extra root certificates to trust.
+    pub ca_bundle: Option<String>,
+    /// PEM file holding a client certificate and its private key.
+    pub client_cert: Option<String>,
+    /// Seconds to wait for a connection.
+    pub connect_timeout: Option<u64>,
+    /// Seconds to wait for a whole request, including the reply.
+    pub timeout: Option<u64>,
+    /// Extra headers sent with every request, as `Name: value`.
+    pub headers: Option<Vec<String>>,
+    /// Variables of ours that tool commands may see; a trailing `*` matches any suffix.
+    pub tool_env_allow: Option<Vec<String>>,
+    /// Variables set for tool commands, as `NAME=value`.
+    pub tool_env: Option<Vec<String>>,
+    /// CPU seconds each tool command may use.
+    pub tool_cpu_seconds: Option<u64>,
+    /// Address space of each tool command, in MiB.
+    pub tool_memory_mb: Option<u64>,
+    /// Largest file a tool command may write, in MiB.
+    pub tool_file_size_mb: Option<u64>,
+    /// Cap on all processes of the user, not just the command's, while a tool command runs.
+    pub tool_max_processes: Option<u64>,
+    /// Tools declared with `[[tools]]` tables; kept last so they serialize after plain values.
+    pub tools: Option<Vec<CustomTool>>,
+    /// Extra secrets to mask, declared with `[[redact]]` tables.
+    pub redact: Option<Vec<RedactPattern>>,
+    /// Set when the config was built from `YUCHI_*` variables; such a config is never written to disk.
+    #[serde(skip)]
+    pub env_only: bool,
+    /// Layer each set value came from; keys that are missing fell back to their default.
+    #[serde(skip)]
+    pub origins: BTreeMap<&'static str, Origin>,
+    /// Directory holding the project file, if one was found.
+    #[serde(skip)]
+    pub project_dir: Option<PathBuf>,
+    /// Session whose channel this run talks in; `None` in environment-only mode.
+    #[serde(skip)]
+    pub session: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            version: SCHEMA_VERSION,
+            api_key: None,
+            app_id: None,
+            user_auth_token: None,
+            shape: None,
+            user_id: None,
+            channel_id: None,
+            tool_policy: None,
+            search_approval: None,
+            sandbox: None,
+            redaction: None,
+            context_files: None,
+            session_mode: None,
+            proxy: None,
+            ca_bundle: None,
+            client_cert: None,
+            connect_timeout: None,
+            timeout: None,
+            headers: None,
+            tool_env_allow: None,
+            tool_env: None,
+            tool_cpu_seconds: None,
+            tool_memory_mb: None,
+            tool_file_size_mb: None,
+            tool_max_processes: None,
+            tools: None,
+            redact: None,
+            env_only: false,
+            origins: BTreeMap::new(),
+            project_dir: None,
+            session: None,
+        }
+    }
 }
 
 impl Config {
+    /// The effective settings: defaults, then the profile's config file, the nearest project file,
+    /// `YUCHI_*` variables and command-line flags, each layer overriding the ones before it.
     pub fn load() -> Result<Self, YuchiError> {
-        confy::load("yuchi", "config")
-            .map_err(|e| YuchiError::Config(format!("Failed to load config: {}", e)))
+        let mut config = if env_only() {
+            Config { env_only: true, ..Config::default() }
+        } else {
+            Self::load_global()?
+        };
+
+        if let Some(path) = find_project_file() {
+            log::debug!(path:% = path.display(); "using project file");
+            let project = Self::read_project(&path)?;
+            config.project_dir = path.parent().map(Path::to_path_buf);
+            config.merge(project, Origin::Project(path));
+        }
+        config.apply_env()?;
+        config.apply_flags()?;
+
+        if config.env_only {
+            // Without a stored config there is nothing to remember IDs in; use fresh ones per run.
+            for key in ["user_id", "channel_id"] {
+                if config.get(key).is_none() {
+                    config.set(key, Some(Uuid::new_v4().to_string()))?;
+                    config.origins.insert(key, Origin::Generated);
+                }
+            }
+        }
+        session::activate(&mut config)?;
+        Ok(config)
+    }
+
+    /// Only the profile's config file and vault: what commands that save settings start from,
+    /// so project, environment and flag values never leak into the file.
+    pub fn load_global() -> Result<Self, YuchiError> {
+        if env_only() {
+            return Self::load();
+        }
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let (mut config, migrated) = Self::read(&path)?;
+        if config.api_key.is_some() || config.user_auth_token.is_some() {
+            vault::check_private(&path)?;
+        }
+        vault::set_private(&path)?;
+        config.mark(Origin::Global(path.clone()));
+
+        if let Some(secrets) = vault::load()? {
+            config.api_key = secrets.api_key;
+            config.user_auth_token = secrets.user_auth_token;
+            let vault_path = vault::vault_path()?;
+            for key in ["api_key", "user_auth_token"] {
+                if config.get(key).is_some() {
+                    config.origins.insert(key, Origin::Vault(vault_path.clone()));
+                }
+            }
+        }
+        if migrated {
+            // Saving moves plaintext secrets into the vault; if that can't happen now, retry next run.
+            if let Err(e) = config.save() {
+                eprintln!("{}", format!("Note: '{}' was upgraded in memory but not saved: {}", path.display(), e).yellow());
+            }
+        }
+        Ok(config)
+    }
+
+    /// Parses a config file, upgrading older layouts. Also reports whether a migration happened.
+    pub fn read(path: &Path) -> Result<(Self, bool), YuchiError> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| YuchiError::io(format!("Failed to read config '{}'", path.display()), e))?;
+        let mut table: toml::Table = text
+            .parse()
+            .map_err(|e| YuchiError::Config(format!("Failed to parse '{}': {}", path.display(), e)))?;
+        let migrated = migrate(&mut table)?;
+        let config = toml::Value::Table(table)
+            .try_into()
+            .map_err(|e| YuchiError::Config(format!("Invalid config '{}': {}", path.display(), e)))?;
+        Ok((config, migrated))
+    }
+
+    /// Parses a project file, which may only pin the settings a repository can safely share.
+    fn read_project(path: &Path) -> Result<Self, YuchiError> {
+        let (project, _) = Self::read(path)?;
+        if let Some(setting) = SETTINGS
+            .iter()
+            .find(|s| !s.project && project.get(s.key).is_some())
+        {
+            return Err(YuchiError::Config(format!(
+                "'{}' cannot be set in the project file '{}'. Project files may only set {}.",
+                setting.key,
+                path.display(),
+                SETTINGS.iter().filter(|s| s.project).map(|s| s.key).collect::<Vec<_>>().join(", ")
+            )));
+        }
+        if let Some((_, e)) = project.invalid_settings().into_iter().next() {
+            return Err(YuchiError::Config(format!("Invalid project file '{}': {}", path.display(), e)));
+        }
+        Ok(project)
+    }
+
+    /// Overrides every setting `layer` has a value for, recording `origin` for it.
+    fn merge(&mut self, layer: Config, origin: Origin) {
+        macro_rules! take {
+            ($($field:ident),*) => {$(
+                if layer.$field.is_some() {
+                    self.$field = layer.$field;
+                    self.origins.insert(stringify!($field), origin.clone());
+                }
+            )*};
+        }
+        take!(
+            api_key, user_auth_token, app_id, shape, user_id, channel_id, tool_policy, search_approval, sandbox,
+            redaction, context_files, session_mode, proxy, ca_bundle, client_cert, connect_timeout, timeout, headers,
+            tool_env_allow, tool_env, tool_cpu_seconds, tool_memory_mb, tool_file_size_mb, tool_max_processes
+        );
+        // Tools add up across layers. A project tool never replaces a global one, so a tool the user
+        // declared always runs the command they wrote, whatever repository they're in.
+        if let Some(tools) = layer.tools {
+            let merged = self.tools.get_or_insert_with(Vec::new);
+            for tool in tools {
+                if let Origin::Project(path) = &origin {
+                    if merged.iter().any(|t| t.name == tool.name) {
+                        log::warn!(
+                            "ignoring tool '{}' from '{}': your config already declares a tool of that name",
+                            tool.name,
+                            path.display()
+                        );
+                        continue;
+                    }
+                }
+                merged.retain(|t| t.name != tool.name);
+                merged.push(tool);
+            }
+            self.origins.insert("tools", origin.clone());
+        }
+        // Patterns only ever add up, so a project can't unmask what the profile masks.
+        if let Some(patterns) = layer.redact {
+            self.redact.get_or_insert_with(Vec::new).extend(patterns);
+            self.origins.insert("redact", origin);
+        }
+    }
+
+    /// Records `origin` for every setting that currently has a value.
+    fn mark(&mut self, origin: Origin) {
+        for setting in SETTINGS {
+            if self.get(setting.key).is_some() {
+                self.origins.insert(setting.key, origin.clone());
+            }
+        }
+    }
+
+    fn apply_env(&mut self) -> Result<(), YuchiError> {
+        for setting in SETTINGS {
+            let Some(value) = env_var(setting.env) else { continue };
+            setting.validate(&value).map_err(|_| {
+                YuchiError::Config(format!("{} must be {}.", setting.env, setting.expected()))
+            })?;
+            self.set(setting.key, Some(value))?;
+            self.origins.insert(setting.key, Origin::Env(setting.env));
+        }
+        Ok(())
+    }
+
+    fn apply_flags(&mut self) -> Result<(), YuchiError> {
+        for &(key, flag, ref value) in FLAGS.get().into_iter().flatten() {
+            self.set(key, Some(value.clone()))?;
+            self.origins.insert(key, Origin::Flag(flag));
+        }
+        Ok(())
+    }
+
+    /// Where the effective value of `key` came from.
+    pub fn origin(&self, key: &str) -> Origin {
+        self.origins.get(key).cloned().unwrap_or(Origin::Default)
+    }
+
+    /// Config file of the active profile; the default profile keeps the original `config.toml`.
+    pub fn path() -> Result<PathBuf, YuchiError> {
+        let name = match profile() {
+            DEFAULT_PROFILE => "config".to_string(),
+            other => format!("profile-{}", other),
+        };
+        confy::get_configuration_file_path("yuchi", name.as_str())
+            .map_err(|e| YuchiError::Config(format!("Failed to locate config file: {}", e)))
     }
 
     pub fn save(&self) -> Result<(), YuchiError> {
-        confy::store("yuchi", "config", self)
-            .map_err(|e| YuchiError::Config(format!("Failed to save config: {}", e)))
+        if self.env_only {
+            return Err(YuchiError::Config(
+                "Environment-only mode is active (YUCHI_API_KEY or YUCHI_USER_AUTH_TOKEN is set); the config file is left untouched.".to_string(),
+            ));
+        }
+        vault::store(&Secrets {
+            api_key: self.api_key.clone(),
+            user_auth_token: self.user_auth_token.clone(),
+        })?;
+
+        let path = Self::path()?;
+        confy::store_path(&path, self)
+            .map_err(|e| YuchiError::Config(format!("Failed to save config: {}", e)))?;
+        vault::set_private(&path)
+    }
+
+    /// The model questions go to: `shapesinc/<shape>`, or the default shape.
+    pub fn model(&self) -> String {
+        self.shape
+            .as_ref()
+            .map(|u| format!("shapesinc/{}", u))
+            .unwrap_or_else(|| DEFAULT_MODEL.to_string())
+    }
+
+    /// The app ID for the user auth token flow, or the built-in app.
+    pub fn app_id(&self) -> String {
+        self.app_id.clone().unwrap_or_else(|| APP_ID.to_string())
+    }
+
+    /// Whether each git repository gets its own session.
+    pub fn project_sessions(&self) -> bool {
+        self.session_mode.as_deref() == Some("project")
+    }
+
+    pub fn tool_policy(&self) -> ToolPolicy {
+        match self.tool_policy.as_deref() {
+            Some("deny") => ToolPolicy::Deny,
+            _ => ToolPolicy::Ask,
+        }
+    }
+
+    /// What tool calls may do in this run.
+    pub fn tool_settings(&self) -> Result<ToolSettings, YuchiError> {
+        Ok(ToolSettings {
+            policy: self.tool_policy(),
+            auto_approve_search: self.search_approval.as_deref() != Some("ask"),
+            custom: self.custom_tools(),
+            sandbox: Sandbox::from_setting(self.sandbox.as_deref()),
+            env: ToolEnv {
+                allow: self.tool_env_allow.clone().unwrap_or_else(|| ToolEnv::default().allow),
+                set: self.tool_env.iter().flatten().filter_map(|var| parse_env_var(var)).collect(),
+            },
+            limits: Limits {
+                cpu_seconds: self.tool_cpu_seconds,
+                memory_mb: self.tool_memory_mb,
+                file_size_mb: self.tool_file_size_mb,
+                processes: self.tool_max_processes,
+            },
+            redactor: self.redactor()?,
+        })
+    }
+
+    /// What to mask in everything sent to the API.
+    pub fn redactor(&self) -> Result<Redactor, YuchiError> {
+        Redactor::new(self.redaction.as_deref(), self.redact.as_deref().unwrap_or_default())
+    }
+
+    /// The declared tools that pass validation; of several with one name, the last one wins.
+    fn custom_tools(&self) -> Vec<CustomTool> {
+        let mut tools: Vec<CustomTool> = Vec::new();
+        for tool in self.tools.iter().flatten() {
+            match tool.validate() {
+                Ok(()) => {
+                    tools.retain(|t| t.name != tool.name);
+                    tools.push(tool.clone());
+                }
+                Err(e) => log::warn!("skipping tool '{}': {}", tool.name, e),
+            }
+        }
+        tools
+    }
+
+    /// The context files to send, resolved against the project directory when a project file set them,
+    /// and the current directory otherwise. Project-pinned files must stay inside the project.
+    pub fn context_paths(&self) -> Result<Vec<PathBuf>, YuchiError> {
+        let Some(files) = &self.context_files else { return Ok(Vec::new()) };
+        let project_dir = match self.origin("context_files") {
+            Origin::Project(_) => self.project_dir.clone(),
+            _ => None,
+        };
+        let base = match &project_dir {
+            Some(dir) => dir.clone(),
+            None => env::current_dir().map_err(|e| YuchiError::io("Failed to read the current directory", e))?,
+        };
+
+        files
+            .iter()
+            .map(|file| {
+                let path = base.join(file);
+                let resolved = path.canonicalize().map_err(|e| {
+                    YuchiError::io(format!("Failed to read context file '{}'", path.display()), e)
+                })?;
+                if let Some(dir) = &project_dir {
+                    let root = dir
+                        .canonicalize()
+                        .map_err(|e| YuchiError::io(format!("Failed to read '{}'", dir.display()), e))?;
+                    if !resolved.starts_with(&root) {
+                        return Err(YuchiError::Config(format!(
+                            "Context file '{}' is outside the project '{}'.",
+                            file,
+                            root.display()
+                        )));
+                    }
+                }
+                Ok(resolved)
+            })
+            .collect()
+    }
+
+    /// Request authentication for the stored credentials, preferring a user auth token.
+    pub fn auth<'a>(&'a self, app_id: &'a str) -> Result<Auth<'a>, YuchiError> {
+        if let Some(token) = &self.user_auth_token {
+            return Ok(Auth::UserToken { token, app_id, channel_id: self.channel_id.as_deref() });
+        }
+        let api_key = self.api_key.as_deref().ok_or_else(|| {
+            YuchiError::Config("No API key or user auth token set. Run `yuchi login` first.".to_string())
+        })?;
+        let user_id = self.user_id.as_deref()
+            .ok_or_else(|| YuchiError::Config("No user ID set. Run `yuchi login` first.".to_string()))?;
+        let channel_id = self.channel_id.as_deref()
+            .ok_or_else(|| YuchiError::Config("No channel ID set. Run `yuchi login` first.".to_string()))?;
+        Ok(Auth::ApiKey { api_key, user_id, channel_id })
+    }
+
+    /// The value of `key` as text; lists are joined with commas.
+    pub fn get(&self, key: &str) -> Option<String> {
+        match key {
+            "api_key" => self.api_key.clone(),
+            "user_auth_token" => self.user_auth_token.clone(),
+            "app_id" => self.app_id.clone(),
+            "shape" => self.shape.clone(),
+            "user_id" => self.user_id.clone(),
+            "channel_id" => self.channel_id.clone(),
+            "tool_policy" => self.tool_policy.clone(),
+            "search_approval" => self.search_approval.clone(),
+            "sandbox" => self.sandbox.clone(),
+            "redaction" => self.redaction.clone(),
+            "context_files" => self.context_files.as_ref().map(|files| files.join(",")),
+            "session_mode" => self.session_mode.clone(),
+            "proxy" => self.proxy.clone(),
+            "ca_bundle" => self.ca_bundle.clone(),
+            "client_cert" => self.client_cert.clone(),
+            "connect_timeout" => self.connect_timeout.map(|secs| secs.to_string()),
+            "timeout" => self.timeout.map(|secs| secs.to_string()),
+            "headers" => self.headers.as_ref().map(|headers| headers.join(",")),
+            "tool_env_allow" => self.tool_env_allow.as_ref().map(|names| names.join(",")),
+            "tool_env" => self.tool_env.as_ref().map(|vars| vars.join(",")),
+            "tool_cpu_seconds" => self.tool_cpu_seconds.map(|n| n.to_string()),
+            "tool_memory_mb" => self.tool_memory_mb.map(|n| n.to_string()),
+            "tool_file_size_mb" => self.tool_file_size_mb.map(|n| n.to_string()),
+            "tool_max_processes" => self.tool_max_processes.map(|n| n.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Sets or clears `key` after checking the value against its [`Setting`]. Lists are comma-separated.
+    pub fn set(&mut self, key: &str, value: Option<String>) -> Result<(), YuchiError> {
+        let setting = Setting::find(key)?;
+        if let Some(value) = &value {
+            setting.validate(value)?;
+        }
+        let field = match setting.key {
+            "context_files" => {
+                self.context_files = value.map(|v| split_list(&v));
+                return Ok(());
+            }
+            "headers" => {
+                self.headers = value.map(|v| split_list(&v));
+                return Ok(());
+            }
+            "tool_env_allow" => {
+                self.tool_env_allow = value.map(|v| split_list(&v));
+                return Ok(());
+            }
+            "tool_env" => {
+                self.tool_env = value.map(|v| split_env_vars(&v));
+                return Ok(());
+            }
+            "tool_cpu_seconds" | "tool_memory_mb" | "tool_file_size_mb" | "tool_max_processes" => {
+                // Validation above guarantees a number.
+                let n = value.and_then(|v| v.parse().ok());
+                match setting.key {
+                    "tool_cpu_seconds" => self.tool_cpu_seconds = n,
+                    "tool_memory_mb" => self.tool_memory_mb = n,
+                    "tool_file_size_mb" => self.tool_file_size_mb = n,
+                    _ => self.tool_max_processes = n,
+                }
+                return Ok(());
+            }
+            "connect_timeout" | "timeout" => {
+                // Validation above guarantees a number.
+                let secs = value.and_then(|v| v.parse().ok());
+                match setting.key {
+                    "connect_timeout" => self.connect_timeout = secs,
+                    _ => self.timeout = secs,
+                }
+                return Ok(());
+            }
+            "proxy" => &mut self.proxy,
+            "ca_bundle" => &mut self.ca_bundle,
+            "client_cert" => &mut self.client_cert,
+            "api_key" => &mut self.api_key,
+            "user_auth_token" => &mut self.user_auth_token,
+            "app_id" => &mut self.app_id,
+            "shape" => &mut self.shape,
+            "user_id" => &mut self.user_id,
+            "tool_policy" => &mut self.tool_policy,
+            "search_approval" => &mut self.search_approval,
+            "sandbox" => &mut self.sandbox,
+            "redaction" => &mut self.redaction,
+            "session_mode" => &mut self.session_mode,
+            _ => &mut self.channel_id,
+        };
+        *field = value;
+        Ok(())
+    }
+
+    /// Every stored value that fails its setting's validation, as `(key, error)`.
+    pub fn invalid_settings(&self) -> Vec<(&'static str, YuchiError)> {
+        SETTINGS
+            .iter()
+            .filter_map(|setting| {
+                let value = self.get(setting.key)?;
+                setting.validate(&value).err().map(|e| (setting.key, e))
+            })
+            .chain(self.tools.iter().flatten().filter_map(|tool| tool.validate().err().map(|e| ("tools", e))))
+            .chain(self.redact.iter().flatten().filter_map(|p| p.validate().err().map(|e| ("redact", e))))
+            .collect()
+    }
+}
+
+/// A tool declared in config. Its arguments are filled into `command`, which then runs like
+/// `run_shell_command`, with the same approval and tool policy.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CustomTool {
+    pub name: String,
+    pub description: String,
+    /// Program and arguments, split like a shell would; `{name}` is replaced by the argument `name`.
+    pub command: String,
+    /// JSON schema of the arguments; no arguments when missing.
+    pub parameters: Option<serde_json::Value>,
+}
+
+impl CustomTool {
+    pub fn validate(&self) -> Result<(), YuchiError> {
+        let invalid = |reason: String| YuchiError::Config(format!("Tool '{}' {}", self.name, reason));
+        if !is_valid_name(&self.name) || self.name.len() > 64 {
+            return Err(invalid("needs a name of at most 64 letters, digits, '-' and '_'.".to_string()));
+        }
+        if tools::is_builtin(&self.name) {
+            return Err(invalid("has the name of a built-in tool.".to_string()));
+        }
+        if self.description.trim().is_empty() {
+            return Err(invalid("needs a description.".to_string()));
+        }
+        let properties = match &self.parameters {
+            None => None,
+            Some(schema) if schema.get("type").and_then(|t| t.as_str()) == Some("object") => {
+                schema.get("properties").and_then(|p| p.as_object())
+            }
+            Some(_) => return Err(invalid("needs parameters with type = \"object\".".to_string())),
+        };
+        let words = shlex::split(&self.command)
+            .filter(|words| !words.is_empty())
+            .ok_or_else(|| invalid(format!("has a command that can't be parsed: `{}`", self.command)))?;
+        for name in words.iter().flat_map(|word| tools::placeholders(word)) {
+            if !properties.is_some_and(|p| p.contains_key(&name)) {
+                return Err(invalid(format!("uses {{{}}} in its command, but has no such parameter.", name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A secret to mask, declared in config. If `pattern` has a capture group, only the group is masked.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RedactPattern {
+    pub name: String,
+    pub pattern: String,
+}
+
+impl RedactPattern {
+    pub fn validate(&self) -> Result<(), YuchiError> {
+        if self.name.trim().is_empty() {
+            return Err(YuchiError::Config(format!("Redaction pattern `{}` needs a name.", self.pattern)));
+        }
+        regex::Regex::new(&self.pattern)
+            .map(|_| ())
+            .map_err(|e| YuchiError::Config(format!("Redaction pattern '{}' is invalid: {}", self.name, e)))
+    }
+}
+
+/// Upgrades an older file layout in place and returns whether anything changed.
+fn migrate(table: &mut toml::Table) -> Result<bool, YuchiError> {
+    let version = table.get("version").and_then(|v| v.as_integer()).unwrap_or(0);
+    if version > SCHEMA_VERSION as i64 {
+        return Err(YuchiError::Config(format!(
+            "The config was written by a newer yuchi (schema version {}). Please upgrade.",
+            version
+        )));
+    }
+    if version == SCHEMA_VERSION as i64 {
+        return Ok(false);
+    }
+
+    // 0 -> 1 (the 0.2.0 layout): `username` became `shape`, and secrets move to the vault on save.
+    if let Some(username) = table.remove("username") {
+        table.entry("shape").or_insert(username);
+    }
+    table.insert("version".to_string(), toml::Value::Integer(SCHEMA_VERSION as i64));
+    Ok(true)
+}
+
+/// How a setting's value is validated and displayed.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Masked on display and stored in the vault.
+    Secret,
+    Uuid,
+    /// A shape username: letters, digits, '-', '_' and '.'.
+    Shape,
+    /// A free-form identifier without whitespace.
+    Id,
+    /// One of a fixed set of words.
+    Choice(&'static [&'static str]),
+    /// Comma-separated paths.
+    Paths,
+    /// A single file path.
+    Path,
+    /// An `http://` or `https://` URL.
+    Url,
+    /// A whole number of seconds, at least 1.
+    Seconds,
+    /// Comma-separated `Name: value` HTTP headers.
+    Headers,
+    /// A whole number, at least 1.
+    Count,
+    /// Comma-separated environment variable names, each optionally ending in `*`.
+    EnvNames,
+    /// Comma-separated `NAME=value` environment variables.
+    EnvVars,
+}
+
+/// A key that `yuchi config` can read and write.
+pub struct Setting {
+    pub key: &'static str,
+    pub kind: Kind,
+    /// Environment variable that overrides the file layers.
+    pub env: &'static str,
+    /// Whether a project's `.yuchi.toml` may set it.
+    pub project: bool,
+    pub description: &'static str,
+}
+
+pub const SETTINGS: &[Setting] = &[
+    Setting { key: "api_key", kind: Kind::Secret, env: "YUCHI_API_KEY", project: false, description: "API key (kept in the encrypted vault)" },
+    Setting { key: "user_auth_token", kind: Kind::Secret, env: "YUCHI_USER_AUTH_TOKEN", project: false, description: "User auth token (kept in the encrypted vault)" },
+    Setting { key: "app_id", kind: Kind::Uuid, env: "YUCHI_APP_ID", project: false, description: "Shapes app ID for the user auth token flow" },
+    Setting { key: "shape", kind: Kind::Shape, env: "YUCHI_SHAPE", project: true, description: "Shape username; questions go to shapesinc/<shape>" },
+    Setting { key: "user_id", kind: Kind::Id, env: "YUCHI_USER_ID", project: false, description: "User ID sent with API key requests" },
+    Setting { key: "channel_id", kind: Kind::Id, env: "YUCHI_CHANNEL_ID", project: false, description: "Channel ID sent with API key requests" },
+    Setting { key: "tool_policy", kind: Kind::Choice(&["ask", "deny"]), env: "YUCHI_TOOL_POLICY", project: true, description: "Tool calls: ask before running, or deny them all" },
+    Setting { key: "search_approval", kind: Kind::Choice(&["auto", "ask"]), env: "YUCHI_SEARCH_APPROVAL", project: true, description: "Search tools (glob_files, search_text): run without asking, or ask first" },
+    Setting { key: "redaction", kind: Kind::Choice(&["all", "patterns", "off"]), env: "YUCHI_REDACTION", project: false, description: "Mask secrets before sending: all detectors, patterns only (no entropy check), or off" },
+    Setting { key: "sandbox", kind: Kind::Choice(&["off", "auto", "bwrap", "namespaces"]), env: "YUCHI_SANDBOX", project: false, description: "Run tool commands with only the project writable and no network (Linux)" },
+    Setting { key: "context_files", kind: Kind::Paths, env: "YUCHI_CONTEXT_FILES", project: true, description: "Files sent as context with every question" },
+    Setting { key: "session_mode", kind: Kind::Choice(&["profile", "project"]), env: "YUCHI_SESSION_MODE", project: true, description: "One conversation per profile, or one per git repository" },
+    Setting { key: "proxy", kind: Kind::Url, env: "YUCHI_PROXY", project: false, description: "HTTP(S) proxy for every request" },
+    Setting { key: "ca_bundle", kind: Kind::Path, env: "YUCHI_CA_BUNDLE", project: false, description: "PEM file of extra root certificates to trust" },
+    Setting { key: "client_cert", kind: Kind::Path, env: "YUCHI_CLIENT_CERT", project: false, description: "PEM file with a client certificate and its key" },
+    Setting { key: "connect_timeout", kind: Kind::Seconds, env: "YUCHI_CONNECT_TIMEOUT", project: false, description: "Seconds to wait for a connection" },
+    Setting { key: "timeout", kind: Kind::Seconds, env: "YUCHI_TIMEOUT", project: false, description: "Seconds to wait for a whole request (default 30)" },
+    Setting { key: "headers", kind: Kind::Headers, env: "YUCHI_HEADERS", project: false, description: "Extra headers for every request, as comma-separated `Name: value`" },
+    Setting { key: "tool_env_allow", kind: Kind::EnvNames, env: "YUCHI_TOOL_ENV_ALLOW", project: false, description: "Variables tool commands inherit (default PATH, HOME, USER, LANG, LC_*, ...)" },
+    Setting { key: "tool_env", kind: Kind::EnvVars, env: "YUCHI_TOOL_ENV", project: false, description: "Variables set for tool commands, as comma-separated `NAME=value`" },
+    Setting { key: "tool_cpu_seconds", kind: Kind::Seconds, env: "YUCHI_TOOL_CPU_SECONDS", project: false, description: "CPU seconds each tool command may use" },
+    Setting { key: "tool_memory_mb", kind: Kind::Count, env: "YUCHI_TOOL_MEMORY_MB", project: false, description: "Memory (address space) each tool command may use, in MiB" },
+    Setting { key: "tool_file_size_mb", kind: Kind::Count, env: "YUCHI_TOOL_FILE_SIZE_MB", project: false, description: "Largest file a tool command may write, in MiB" },
+    Setting { key: "tool_max_processes", kind: Kind::Count, env: "YUCHI_TOOL_MAX_PROCESSES", project: false, description: "Per-user cap, not per command: counts all of your processes while a tool command runs" },
+];
+
+impl Setting {
+    pub fn find(key: &str) -> Result<&'static Setting, YuchiError> {
+        SETTINGS.iter().find(|s| s.key == key).ok_or_else(|| {
+            let keys: Vec<&str> = SETTINGS.iter().map(|s| s.key).collect();
+            YuchiError::Input(format!("Unknown config key '{}'. Known keys: {}", key, keys.join(", ")))
+        })
+    }
+
+    pub fn validate(&self, value: &str) -> Result<(), YuchiError> {
+        let valid = match self.kind {
+            Kind::Secret => !value.trim().is_empty(),
+            Kind::Uuid => Uuid::parse_str(value).is_ok(),
+            Kind::Shape => {
+                !value.is_empty()
+                    && value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            }
+            Kind::Id => !value.is_empty() && !value.chars().any(char::is_whitespace),
+            Kind::Choice(choices) => choices.contains(&value),
+            Kind::Paths => !split_list(value).is_empty(),
+            Kind::Path => !value.trim().is_empty(),
+            Kind::Url => reqwest::Url::parse(value).is_ok_and(|url| matches!(url.scheme(), "http" | "https")),
+            Kind::Seconds => value.parse::<u64>().is_ok_and(|secs| secs > 0),
+            Kind::Headers => {
+                let headers = split_list(value);
+                !headers.is_empty() && headers.iter().all(|header| parse_header(header).is_some())
+            }
+            Kind::Count => value.parse::<u64>().is_ok_and(|n| n > 0),
+            Kind::EnvNames => {
+                let names = split_list(value);
+                !names.is_empty() && names.iter().all(|name| is_env_name(name.strip_suffix('*').unwrap_or(name)))
+            }
+            Kind::EnvVars => {
+                let vars = split_env_vars(value);
+                !vars.is_empty() && vars.iter().all(|var| parse_env_var(var).is_some())
+            }
+        };
+        if valid {
+            return Ok(());
+        }
+        Err(YuchiError::Input(format!("'{}' must be {}.", self.key, self.expected())))
+    }
+
+    pub fn expected(&self) -> String {
+        match self.kind {
+            Kind::Secret => "a non-empty value".to_string(),
+            Kind::Uuid => "a UUID".to_string(),
+            Kind::Shape => "a shape username (letters, digits, '-', '_' and '.')".to_string(),
+            Kind::Id => "an identifier without whitespace".to_string(),
+            Kind::Choice(choices) => format!("one of {}", choices.join(", ")),
+            Kind::Paths => "a comma-separated list of paths".to_string(),
+            Kind::Path => "a file path".to_string(),
+            Kind::Url => "an http:// or https:// URL".to_string(),
+            Kind::Seconds => "a whole number of seconds, at least 1".to_string(),
+            Kind::Headers => "comma-separated `Name: value` headers".to_string(),
+            Kind::Count => "a whole number, at least 1".to_string(),
+            Kind::EnvNames => "comma-separated variable names, each optionally ending in `*`".to_string(),
+            Kind::EnvVars => "comma-separated `NAME=value` variables".to_string(),
+        }
+    }
+
+    /// The value as it may be printed: secrets keep only their first and last characters.
+    pub fn display(&self, value: &str, reveal: bool) -> String {
+        if self.kind == Kind::Url && !reveal {
+            // Proxy URLs may carry credentials.
+            if let Ok(mut url) = reqwest::Url::parse(value) {
+                if url.password().is_some() && url.set_password(Some("********")).is_ok() {
+                    return url.to_string();
+                }
+            }
+        }
+        if self.kind != Kind::Secret || reveal {
+            return value.to_string();
+        }
+        let chars: Vec<char> = value.chars().collect();
+        if chars.len() <= 12 {
+            return "********".to_string();
+        }
+        let head: String = chars[..4].iter().collect();
+        let tail: String = chars[chars.len() - 4..].iter().collect();
+        format!("{}…{}", head, tail)
+    }
+}
+
+/// The nearest project file in the current directory or one of its parents.
+fn find_project_file() -> Option<PathBuf> {
+    let cwd = env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(PROJECT_FILE))
+        .find(|path| path.is_file())
+}
+
+/// Splits a `Name: value` header into a valid name and value.
+pub fn parse_header(header: &str) -> Option<(reqwest::header::HeaderName, reqwest::header::HeaderValue)> {
+    let (name, value) = header.split_once(':')?;
+    let name = reqwest::header::HeaderName::from_bytes(name.trim().as_bytes()).ok()?;
+    let value = reqwest::header::HeaderValue::from_str(value.trim()).ok()?;
+    Some((name, value))
+}
+
+/// Splits `NAME=value` into a valid variable name and its value.
+pub fn parse_env_var(var: &str) -> Option<(String, String)> {
+    let (name, value) = var.split_once('=')?;
+    let name = name.trim();
+    is_env_name(name).then(|| (name.to_string(), value.trim().to_string()))
+}
+
+/// Letters, digits and `_`, not starting with a digit. An empty name is allowed for a lone `*`.
+fn is_env_name(name: &str) -> bool {
+    !name.starts_with(|c: char| c.is_ascii_digit()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Splits comma-separated `NAME=value` variables only where a comma is followed by the next name,
+/// so values like `RUSTFLAGS=-C opt-level=2,-C debuginfo=0` keep their commas.
+fn split_env_vars(value: &str) -> Vec<String> {
+    let mut vars: Vec<String> = Vec::new();
+    for part in value.split(',') {
+        let name = part.split_once('=').map(|(name, _)| name.trim());
+        let starts_var = name.is_some_and(|name| !name.is_empty() && is_env_name(name));
+        match vars.last_mut() {
+            Some(var) if !starts_var => {
+                var.push(',');
+                var.push_str(part);
+            }
+            _ => vars.push(part.to_string()),
+        }
+    }
+    vars.iter().map(|var| var.trim()).filter(|var| !var.is_empty()).map(str::to_string).collect()
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_env_vars() {
+        assert_eq!(parse_env_var(" CI = true "), Some(("CI".to_string(), "true".to_string())));
+        assert_eq!(parse_env_var("OPTS=a=b"), Some(("OPTS".to_string(), "a=b".to_string())));
+        assert_eq!(parse_env_var("1ST=x"), None);
+        assert_eq!(parse_env_var("MY-VAR=x"), None);
+        assert_eq!(parse_env_var("NO_VALUE"), None);
+    }
+
+    #[test]
+    fn splits_env_vars_only_before_names() {
+        let vars = split_env_vars("RUSTFLAGS=-C opt-level=2,-C debuginfo=0, NODE_OPTIONS=--a,--b=1,CI=1");
+        assert_eq!(vars, ["RUSTFLAGS=-C opt-level=2,-C debuginfo=0", "NODE_OPTIONS=--a,--b=1", "CI=1"]);
+        assert_eq!(split_env_vars("SEP=,,B=y"), ["SEP=,", "B=y"]);
+        assert!(split_env_vars("  ").is_empty());
+    }
+
+    #[test]
+    fn tool_env_keeps_commas_in_values() {
+        let mut config = Config::default();
+        config.set("tool_env", Some("LIST=a,b,c,CI=1".to_string())).unwrap();
+        assert_eq!(config.tool_env, Some(vec!["LIST=a,b,c".to_string(), "CI=1".to_string()]));
+        assert!(config.set("tool_env", Some("not a var".to_string())).is_err());
     }
 }
@@ -0,0 +1,288 @@
+use crate::api::{self, validate_credentials, BASE_URL};
+use crate::commands::DOWNLOAD_DIR;
+use crate::config::{self, Config, DEFAULT_MODEL};
+use crate::errors::YuchiError;
+use crate::ui::{display_progress, display_table};
+use crate::vault;
+use colored::Colorize;
+use reqwest::blocking::Client;
+use std::error::Error;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+    Skip,
+}
+
+impl Status {
+    fn label(self) -> String {
+        match self {
+            Status::Pass => "PASS".green().bold().to_string(),
+            Status::Warn => "WARN".yellow().bold().to_string(),
+            Status::Fail => "FAIL".red().bold().to_string(),
+            Status::Skip => "SKIP".dimmed().to_string(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    rows: Vec<Vec<String>>,
+    /// The first failure, returned so the exit code and hint match what went wrong.
+    first_failure: Option<YuchiError>,
+}
+
+impl Report {
+    fn add(&mut self, check: &str, status: Status, detail: impl Into<String>) {
+        self.rows.push(vec![check.to_string(), status.label(), detail.into()]);
+    }
+
+    fn fail(&mut self, check: &str, error: YuchiError) {
+        self.add(check, Status::Fail, error.to_string());
+        self.first_failure.get_or_insert(error);
+    }
+}
+
+/// Runs every check in order, printing a pass/fail table. Fails with the first failing check's error.
+pub fn run() -> Result<(), YuchiError> {
+    let mut report = Report::default();
+
+    check_config_file(&mut report);
+    let config = match Config::load() {
+        Ok(config) => {
+            report.add("Config", Status::Pass, format!("profile '{}' loads and validates", config::profile()));
+            Some(config)
+        }
+        Err(e) => {
+            report.fail("Config", e);
+            None
+        }
+    };
+    let logged_in = config.as_ref().is_some_and(|c| check_credentials(&mut report, c));
+
+    let pb = display_progress();
+    pb.set_message("Checking network...");
+    check_dns(&mut report);
+    let client = check_tls(&mut report, config.as_ref());
+    pb.set_message("Checking credentials...");
+    let auth_ok = match (&config, &client) {
+        (Some(config), Some(client)) if logged_in => check_auth(&mut report, client, config),
+        _ => {
+            report.add("Authentication", Status::Skip, "needs stored credentials and a reachable API");
+            false
+        }
+    };
+    match (&config, &client) {
+        (Some(config), Some(client)) if auth_ok => check_shape(&mut report, client, config),
+        _ => report.add("Shape", Status::Skip, "needs valid credentials"),
+    }
+    pb.finish_and_clear();
+
+    check_download_dir(&mut report);
+    check_terminal(&mut report);
+
+    display_table(&["Check", "Status", "Details"], &report.rows);
+    match report.first_failure {
+        Some(e) => Err(e),
+        None => {
+            println!("{}", "No problems found.".green());
+            Ok(())
+        }
+    }
+}
+
+fn check_config_file(report: &mut Report) {
+    let path = match Config::path() {
+        Ok(path) => path,
+        Err(e) => return report.fail("Config file", e),
+    };
+    if !path.exists() {
+        return report.add(
+            "Config file",
+            Status::Warn,
+            format!("{} does not exist yet; run `yuchi login`", path.display()),
+        );
+    }
+    match loose_permissions(&path) {
+        Some(mode) => report.add(
+            "Config file",
+            Status::Warn,
+            format!("{} is readable by other users (mode {:o}); run `chmod 600`", path.display(), mode),
+        ),
+        None => report.add("Config file", Status::Pass, path.display().to_string()),
+    }
+
+    match vault::vault_path() {
+        Ok(vault_path) if vault_path.exists() => match loose_permissions(&vault_path) {
+            Some(mode) => report.add(
+                "Vault",
+                Status::Warn,
+                format!("{} is readable by other users (mode {:o})", vault_path.display(), mode),
+            ),
+            None => report.add("Vault", Status::Pass, vault_path.display().to_string()),
+        },
+        Ok(vault_path) => report.add("Vault", Status::Skip, format!("{} does not exist yet", vault_path.display())),
+        Err(e) => report.fail("Vault", e),
+    }
+}
+
+/// The file's mode when group or others can access it.
+fn loose_permissions(path: &Path) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path).ok()?.permissions().mode() & 0o777;
+        (mode & 0o077 != 0).then_some(mode)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+fn check_credentials(report: &mut Report, config: &Config) -> bool {
+    let method = if config.user_auth_token.is_some() {
+        "user auth token"
+    } else if config.api_key.is_some() {
+        "API key"
+    } else {
+        report.fail(
+            "Credentials",
+            YuchiError::Config("Not logged in. Run `yuchi login` first.".to_string()),
+        );
+        return false;
+    };
+    let key = if config.user_auth_token.is_some() { "user_auth_token" } else { "api_key" };
+    report.add("Credentials", Status::Pass, format!("{} from {}", method, config.origin(key)));
+    true
+}
+
+/// Only a warning: behind a proxy the API can be reachable without resolving it locally.
+fn check_dns(report: &mut Report) {
+    let host = reqwest::Url::parse(BASE_URL)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    match (host.as_str(), 443).to_socket_addrs() {
+        Ok(addrs) => {
+            let addrs: Vec<String> = addrs.map(|a| a.ip().to_string()).collect();
+            report.add("DNS", Status::Pass, format!("{} -> {}", host, addrs.join(", ")));
+        }
+        Err(e) => report.add("DNS", Status::Warn, format!("Failed to resolve {}: {}", host, e)),
+    }
+}
+
+/// Uses the configured proxy, certificates and headers when the config loads. Returns the
+/// client on success so the remaining checks reuse its connection.
+fn check_tls(report: &mut Report, config: Option<&Config>) -> Option<Client> {
+    let client = match config {
+        Some(config) => api::client(config),
+        None => Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| YuchiError::network("Failed to build the HTTP client", e)),
+    };
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            report.fail("TLS", e);
+            return None;
+        }
+    };
+    match client.get(BASE_URL).send() {
+        Ok(res) => {
+            report.add("TLS", Status::Pass, format!("{} answered {}", BASE_URL, res.status()));
+            Some(client)
+        }
+        Err(e) => {
+            let cause = root_cause(&e);
+            report.fail("TLS", YuchiError::network(format!("Failed to reach {} ({})", BASE_URL, cause), e));
+            None
+        }
+    }
+}
+
+/// The innermost error message, where TLS and connection details usually are.
+fn root_cause(error: &dyn Error) -> String {
+    let mut cause = error;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    cause.to_string()
+}
+
+fn check_auth(report: &mut Report, client: &Client, config: &Config) -> bool {
+    let app_id = config.app_id();
+    let result = config.auth(&app_id).and_then(|auth| validate_credentials(client, &auth, DEFAULT_MODEL));
+    match result {
+        Ok(()) => {
+            report.add("Authentication", Status::Pass, "credentials accepted");
+            true
+        }
+        Err(e) => {
+            report.fail("Authentication", e);
+            false
+        }
+    }
+}
+
+fn check_shape(report: &mut Report, client: &Client, config: &Config) {
+    let model = config.model();
+    if config.shape.is_none() {
+        return report.add("Shape", Status::Pass, format!("{} (default)", model));
+    }
+    let app_id = config.app_id();
+    let result = config.auth(&app_id).and_then(|auth| validate_credentials(client, &auth, &model));
+    match result {
+        Ok(()) => report.add("Shape", Status::Pass, format!("{} exists", model)),
+        Err(e) => report.fail("Shape", e),
+    }
+}
+
+/// Only `yuchi imagine` needs the download directory, so problems are warnings.
+fn check_download_dir(report: &mut Report) {
+    let dir = Path::new(DOWNLOAD_DIR);
+    if !dir.is_dir() {
+        return report.add("Download directory", Status::Warn, format!("{} does not exist", DOWNLOAD_DIR));
+    }
+    let probe = dir.join(format!(".yuchi-doctor-{}", Uuid::new_v4()));
+    match fs::write(&probe, b"") {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+            report.add("Download directory", Status::Pass, format!("{} is writable", DOWNLOAD_DIR));
+        }
+        Err(e) => report.add("Download directory", Status::Warn, format!("{} is not writable: {}", DOWNLOAD_DIR, e)),
+    }
+}
+
+fn check_terminal(report: &mut Report) {
+    let stdin = io::stdin().is_terminal();
+    let stdout = io::stdout().is_terminal();
+    let term = std::env::var("TERM").unwrap_or_else(|_| "unset".to_string());
+    let colors = colored::control::SHOULD_COLORIZE.should_colorize();
+    let detail = format!(
+        "stdin {}, stdout {}, TERM={}, colors {}",
+        if stdin { "is a terminal" } else { "is not a terminal" },
+        if stdout { "is a terminal" } else { "is not a terminal" },
+        term,
+        if colors { "on" } else { "off" }
+    );
+    if stdin {
+        report.add("Terminal", Status::Pass, detail);
+    } else {
+        report.add(
+            "Terminal",
+            Status::Warn,
+            format!("{}; prompts such as tool approval can't be answered", detail),
+        );
+    }
+}
@@ -1,24 +1,134 @@
+use reqwest::StatusCode;
+use std::error::Error;
 use std::fmt;
+use std::io;
+
+/// Process exit codes, one per kind of failure, so scripts can react without parsing messages.
+///
+/// | Code | Meaning                                               |
+/// |------|-------------------------------------------------------|
+/// | 1    | Unexpected API response, I/O or parse failure         |
+/// | 2    | Invalid input or command-line usage                   |
+/// | 3    | Credentials rejected (run `yuchi login` again)        |
+/// | 4    | Network failure: DNS, connection, TLS or timeout      |
+/// | 5    | Rate-limited by ShapesAI                              |
+/// | 6    | Missing or invalid configuration                      |
+/// | 7    | A tool could not be run                               |
+/// | 8    | The image could not be read or is unsupported         |
+/// | 130  | Cancelled by the user                                 |
+pub mod exit_code {
+    pub const FAILURE: i32 = 1;
+    pub const USAGE: i32 = 2;
+    pub const AUTH: i32 = 3;
+    pub const NETWORK: i32 = 4;
+    pub const RATE_LIMITED: i32 = 5;
+    pub const CONFIG: i32 = 6;
+    pub const TOOL: i32 = 7;
+    pub const IMAGE: i32 = 8;
+    pub const CANCELLED: i32 = 130;
+}
 
 #[derive(Debug)]
 pub enum YuchiError {
+    /// ShapesAI answered, but not in the shape we expected.
     Api(String),
+    /// ShapesAI answered with an error status.
+    Http {
+        status: StatusCode,
+        message: String,
+        body: String,
+    },
+    /// The request never got an answer: DNS, connection, TLS or timeout failures.
+    Network {
+        context: String,
+        source: reqwest::Error,
+    },
+    /// The API rejected the stored credentials (HTTP 401/403).
+    Auth(String),
+    /// HTTP 429, with the server's `Retry-After` in seconds when it sent one.
+    RateLimited { retry_after: Option<u64> },
     Config(String),
     Input(String),
     Image(String),
     Tool(String),
+    Io {
+        context: String,
+        source: io::Error,
+    },
+    Json {
+        context: String,
+        source: serde_json::Error,
+    },
+    /// The user backed out of a prompt (Ctrl-C, closed input).
+    Cancelled(String),
+}
+
+impl YuchiError {
+    pub fn network(context: impl Into<String>, source: reqwest::Error) -> Self {
+        YuchiError::Network { context: context.into(), source }
+    }
+
+    pub fn io(context: impl Into<String>, source: io::Error) -> Self {
+        YuchiError::Io { context: context.into(), source }
+    }
+
+    pub fn json(context: impl Into<String>, source: serde_json::Error) -> Self {
+        YuchiError::Json { context: context.into(), source }
+    }
+
+    /// Maps a failed terminal prompt, treating interrupted or closed input as a cancellation.
+    pub fn prompt(source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::Interrupted | io::ErrorKind::UnexpectedEof => {
+                YuchiError::Cancelled("Input was closed.".to_string())
+            }
+            _ => YuchiError::io("Failed to read input", source),
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            YuchiError::Api(_) | YuchiError::Http { .. } | YuchiError::Io { .. } | YuchiError::Json { .. } => {
+                exit_code::FAILURE
+            }
+            YuchiError::Input(_) => exit_code::USAGE,
+            YuchiError::Auth(_) => exit_code::AUTH,
+            YuchiError::Network { .. } => exit_code::NETWORK,
+            YuchiError::RateLimited { .. } => exit_code::RATE_LIMITED,
+            YuchiError::Config(_) => exit_code::CONFIG,
+            YuchiError::Tool(_) => exit_code::TOOL,
+            YuchiError::Image(_) => exit_code::IMAGE,
+            YuchiError::Cancelled(_) => exit_code::CANCELLED,
+        }
+    }
 }
 
 impl fmt::Display for YuchiError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             YuchiError::Api(msg) => write!(f, "API Error: {}", msg),
+            YuchiError::Http { status, message, .. } => write!(f, "API Error: {} ({})", message, status),
+            YuchiError::Network { context, source } => write!(f, "Network Error: {}: {}", context, source),
+            YuchiError::Auth(msg) => write!(f, "Auth Error: {}", msg),
+            YuchiError::RateLimited { .. } => write!(f, "API Error: Blame Shapes, I got rate-limited. Try again later."),
             YuchiError::Config(msg) => write!(f, "Config Error: {}", msg),
             YuchiError::Input(msg) => write!(f, "Input Error: {}", msg),
             YuchiError::Image(msg) => write!(f, "Image Error: {}", msg),
             YuchiError::Tool(msg) => write!(f, "Tool Error: {}", msg),
+            YuchiError::Io { context, source } => write!(f, "IO Error: {}: {}", context, source),
+            YuchiError::Json { context, source } => write!(f, "Parse Error: {}: {}", context, source),
+            YuchiError::Cancelled(msg) => write!(f, "Cancelled: {}", msg),
         }
     }
 }
 
-impl std::error::Error for YuchiError {}
\ No newline at end of file
+impl Error for YuchiError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            YuchiError::Network { source, .. } => Some(source),
+            YuchiError::Io { source, .. } => Some(source),
+            YuchiError::Json { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::errors::YuchiError;
+use crate::vault;
+use log::LevelFilter;
+use reqwest::blocking::Request;
+use reqwest::StatusCode;
+use serde_json::{json, Map, Value};
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Log filter for stderr in `env_logger` syntax, e.g. `debug` or `yuchi=trace`.
+pub const LOG_ENV: &str = "YUCHI_LOG";
+
+/// File to append redacted request/response records to, like `--trace-file`.
+pub const TRACE_ENV: &str = "YUCHI_TRACE_FILE";
+
+/// Headers that carry credentials and never reach a log.
+const SECRET_HEADERS: &[&str] = &["authorization", "x-user-auth"];
+
+/// JSON fields that carry credentials and never reach a log.
+const SECRET_FIELDS: &[&str] = &["api_key", "auth_token", "code"];
+
+static TRACE: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Sets up stderr logging and the trace file. Warnings are always shown; `YUCHI_LOG` sets the
+/// filter, and each `-v` raises yuchi's own level: info, debug, then trace.
+pub fn init(verbosity: u8, trace_file: Option<&Path>) -> Result<(), YuchiError> {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(LevelFilter::Warn);
+    if let Ok(spec) = env::var(LOG_ENV) {
+        builder.parse_filters(&spec);
+    }
+    let level = match verbosity {
+        0 => None,
+        1 => Some(LevelFilter::Info),
+        2 => Some(LevelFilter::Debug),
+        _ => Some(LevelFilter::Trace),
+    };
+    if let Some(level) = level {
+        builder.filter_module("yuchi", level);
+    }
+    let _ = builder.try_init();
+
+    let path = trace_file
+        .map(Path::to_path_buf)
+        .or_else(|| env::var_os(TRACE_ENV).filter(|v| !v.is_empty()).map(PathBuf::from));
+    if let Some(path) = path {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options
+            .open(&path)
+            .map_err(|e| YuchiError::io(format!("Failed to open trace file '{}'", path.display()), e))?;
+        // The mode above only applies to a new file, and the bodies of exchanges mustn't go to one others can read.
+        // A pipe or terminal is left as it is.
+        if file.metadata().is_ok_and(|metadata| metadata.is_file()) {
+            vault::set_private(&path)?;
+        }
+        let _ = TRACE.set(Mutex::new(file));
+        log::info!(path:% = path.display(); "tracing API exchanges");
+    }
+    Ok(())
+}
+
+/// Logs a request about to be sent and appends it, redacted, to the trace file.
+pub fn trace_request(request: &Request) {
+    log::debug!(method:% = request.method(), url:% = request.url(); "sending request");
+    if TRACE.get().is_none() {
+        return;
+    }
+
+    let headers: Map<String, Value> = request
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            let value = if SECRET_HEADERS.contains(&name.as_str()) {
+                "[redacted]".to_string()
+            } else {
+                value.to_str().unwrap_or("[binary]").to_string()
+            };
+            (name.to_string(), Value::String(value))
+        })
+        .collect();
+    let body = request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .map(|bytes| match serde_json::from_slice::<Value>(bytes) {
+            Ok(value) => redact(value),
+            Err(_) => Value::String(format!("[{} bytes]", bytes.len())),
+        });
+
+    write_record(json!({
+        "type": "request",
+        "method": request.method().as_str(),
+        "url": request.url().as_str(),
+        "headers": headers,
+        "body": body,
+    }));
+}
+
+/// Logs a response body and appends it, redacted, to the trace file.
+pub fn trace_response(url: &str, status: StatusCode, body: &str) {
+    log::debug!(status = status.as_u16(), url, bytes = body.len(); "received response");
+    if TRACE.get().is_none() {
+        return;
+    }
+    let body = match serde_json::from_str::<Value>(body) {
+        Ok(value) => redact(value),
+        Err(_) => Value::String(body.to_string()),
+    };
+    write_record(json!({
+        "type": "response",
+        "url": url,
+        "status": status.as_u16(),
+        "body": body,
+    }));
+}
+
+fn write_record(mut record: Value) {
+    let Some(trace) = TRACE.get() else { return };
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+    record["time"] = json!(time);
+    if let Ok(mut file) = trace.lock() {
+        if let Err(e) = writeln!(file, "{}", record) {
+            log::warn!("failed to write trace record: {}", e);
+        }
+    }
+}
+
+/// Blanks credentials and replaces inline base64 data (images) with its size.
+fn redact(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| {
+                    let value = if SECRET_FIELDS.contains(&key.as_str()) && !value.is_null() {
+                        Value::String("[redacted]".to_string())
+                    } else {
+                        redact(value)
+                    };
+                    (key, value)
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(redact).collect()),
+        Value::String(text) => match text.split_once(";base64,") {
+            Some((prefix, data)) if prefix.starts_with("data:") => {
+                Value::String(format!("{};base64,[{} bytes redacted]", prefix, data.len()))
+            }
+            _ => Value::String(text),
+        },
+        other => other,
+    }
+}
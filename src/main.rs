@@ -1,108 +1,386 @@
+mod agent;
 mod api;
+mod approvals;
+mod backup;
 mod commands;
 mod config;
+mod doctor;
 mod errors;
+mod logging;
+mod patch;
+mod process;
+mod redact;
+mod sandbox;
+mod schema;
+mod session;
+mod tools;
 mod ui;
+mod vault;
 
-use clap::Parser;
+use clap::error::{ContextKind, ContextValue, ErrorKind};
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use crate::commands::Context;
 use crate::errors::YuchiError;
-use crate::ui::{display_error, display_help};
+use crate::ui::display_error;
 
 #[derive(Parser)]
-#[command(version = "0.2.0", about = "Yuchi CLI - A command-line assistant powered by ShapesAI")]
+#[command(
+    version = "0.2.0",
+    about = "Yuchi CLI - A command-line assistant powered by ShapesAI",
+    after_help = "Multi-word questions can be entered without quotes: `yuchi hows you` is short for `yuchi ask hows you`. \
+A question that is just a command name, like `yuchi lock`, runs the command; use `yuchi ask lock` to ask it.\n\n\
+Settings are layered: defaults, the profile's config file, the nearest .yuchi.toml in the current directory or its \
+parents, YUCHI_* variables, then flags. `yuchi config list --show-origin` shows where each value came from.\n\n\
+Environment-only mode: set YUCHI_API_KEY (or YUCHI_USER_AUTH_TOKEN, with optional YUCHI_APP_ID), plus optional \
+YUCHI_SHAPE, YUCHI_USER_ID and YUCHI_CHANNEL_ID, to run without reading or writing the config file.\n\n\
+Credentials are stored encrypted; set YUCHI_VAULT_PASSPHRASE to unlock the vault without a prompt."
+)]
 struct Cli {
-    /// Path to an image file (PNG/JPEG) to send to the AI
+    #[command(flatten)]
+    global: GlobalArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path to an image file (PNG/JPEG) to send with the question
     #[arg(long, value_name = "IMAGE_PATH")]
     image: Option<String>,
 
+    /// Question to ask (shortcut for `yuchi ask`)
+    #[arg(value_name = "QUESTION")]
+    question: Vec<String>,
+}
+
+#[derive(Args)]
+struct GlobalArgs {
+    /// Log more to stderr: -v for requests and timings, -vv for debug details (see also YUCHI_LOG)
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Append every API request and response, with credentials and image data redacted, as JSON lines
+    /// (default: YUCHI_TRACE_FILE)
+    #[arg(long, value_name = "PATH", global = true)]
+    trace_file: Option<PathBuf>,
+
+    /// Profile to use (default: YUCHI_PROFILE or "default")
+    #[arg(long, value_name = "NAME", global = true)]
+    profile: Option<String>,
+
+    /// Shapes app ID for the user auth token flow (default: YUCHI_APP_ID, the profile's, or yuchi's own)
+    #[arg(long, value_name = "APP_ID", global = true)]
+    app_id: Option<String>,
+
+    /// Conversation to talk in (default: YUCHI_SESSION, the repository's in project mode, or the profile's)
+    #[arg(long, value_name = "NAME", global = true)]
+    session: Option<String>,
+
     /// Override the model for this question
-    #[arg(long, value_name = "MODEL")]
+    #[arg(long, value_name = "MODEL", global = true)]
     model: Option<String>,
 
-    /// Reset the AI conversation history (sends '!reset' to AI)
-    #[arg(long)]
-    reset: bool,
+    /// How to treat tool calls: ask or deny (default: YUCHI_TOOL_POLICY, the project's, the profile's, or ask)
+    #[arg(long, value_name = "POLICY", global = true)]
+    tool_policy: Option<String>,
+
+    /// File to send as context with every question; repeat or separate with commas
+    #[arg(long, value_name = "PATH", global = true, value_delimiter = ',')]
+    context_file: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Ask a single question
+    Ask {
+        /// Path to an image file (PNG/JPEG) to send with the question
+        #[arg(long, value_name = "IMAGE_PATH")]
+        image: Option<String>,
 
+        /// Question to ask
+        #[arg(value_name = "QUESTION", required = true)]
+        question: Vec<String>,
+    },
+    /// Start an interactive conversation
+    Chat,
+    /// Authenticate with ShapesAI (API key or user auth token)
+    Login(LoginArgs),
+    /// Clear stored credentials and configuration
+    Logout,
+    /// Show the active profile, auth method, shape and IDs
+    Whoami,
+    /// Check config, credentials, network, shape and terminal, and report what's wrong
+    Doctor,
+    /// Set a ShapesAI username to use a custom model (shapesinc/<username>)
+    Shape {
+        #[arg(value_name = "USERNAME")]
+        username: String,
+    },
+    /// Reset the AI conversation history (sends '!reset' to AI)
+    Reset,
     /// Clear the AI's short-term memory (sends '!wack' to AI)
-    #[arg(long)]
-    wack: bool,
+    Wack,
+    /// Save the current conversation state (sends '!sleep' to AI)
+    Sleep,
+    /// Generate an image and download it (appends '!imagine' to the prompt)
+    Imagine {
+        /// Path to an image file (PNG/JPEG) to send with the prompt
+        #[arg(long, value_name = "IMAGE_PATH")]
+        image: Option<String>,
 
-    /// Save the current conversation state
-    #[arg(long)]
-    sleep: bool,
+        /// What to draw
+        #[arg(value_name = "PROMPT")]
+        prompt: Vec<String>,
+    },
+    /// Revert the last file change a tool made in this project
+    Undo {
+        /// Restore the files even if they were changed again since
+        #[arg(long)]
+        force: bool,
+    },
+    /// Unlock the credential vault and cache its key in a background agent
+    Unlock {
+        /// Seconds to keep the key cached
+        #[arg(long, value_name = "SECONDS", default_value_t = agent::DEFAULT_TTL_SECS)]
+        ttl: u64,
+    },
+    /// Stop the vault agent and forget the cached key
+    Lock,
+    /// Read, change and check the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// List and manage conversation sessions
+    #[command(subcommand)]
+    Session(SessionCommand),
+    /// Run the vault agent (started by `yuchi unlock`)
+    #[command(hide = true)]
+    VaultAgent,
+}
 
-    /// Authenticate with ShapesAI
-    #[arg(long)]
-    login: bool,
+#[derive(Args)]
+struct LoginArgs {
+    /// Read the API key from this environment variable instead of prompting
+    #[arg(long, value_name = "VAR")]
+    api_key_env: Option<String>,
 
-    /// Clear stored credentials and configuration
+    /// Read the API key from this file instead of prompting
+    #[arg(long, value_name = "PATH")]
+    api_key_file: Option<PathBuf>,
+
+    /// Read the API key from stdin instead of prompting
     #[arg(long)]
-    logout: bool,
+    api_key_stdin: bool,
 
-    /// Set a ShapesAI username to use a custom model (shapesinc/<username>)
-    #[arg(long, value_name = "USERNAME")]
-    shape: Option<String>,
+    /// One-time code for the user auth token flow
+    #[arg(long, value_name = "CODE")]
+    code: Option<String>,
+}
 
-    /// Generate an image and download it (appends '!imagine' to the prompt)
-    #[arg(long)]
-    imagine: bool,
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print one setting
+    Get {
+        key: String,
+        /// Show secrets unmasked
+        #[arg(long)]
+        reveal: bool,
+    },
+    /// Change one setting
+    Set { key: String, value: String },
+    /// Remove one setting
+    Unset { key: String },
+    /// Print every effective setting
+    List {
+        /// Show secrets unmasked
+        #[arg(long)]
+        reveal: bool,
+        /// Show which layer (default, global, project, environment, flag) each value came from
+        #[arg(long)]
+        show_origin: bool,
+    },
+    /// Open the config file in $VISUAL or $EDITOR
+    Edit,
+    /// Check every setting against the schema
+    Validate,
+    /// Print the path of the active profile's config file
+    Path,
+}
 
-    /// Question to ask
-    #[arg(value_name = "QUESTION")]
-    question: Vec<String>,
+#[derive(Subcommand)]
+enum SessionCommand {
+    /// Print every session of the profile
+    List,
+    /// Give a session a new name
+    Rename { old: String, new: String },
+    /// Start a new session in a fresh channel, seeded with a summary of another
+    Fork {
+        /// Name of the new session
+        name: String,
+        /// Session to fork (default: the active one)
+        #[arg(long, value_name = "NAME")]
+        from: Option<String>,
+    },
+    /// Forget a session and its transcript
+    Delete { name: String },
 }
 
 fn main() {
     if let Err(e) = run() {
         display_error(&e);
-        std::process::exit(1);
+        // See `errors::exit_code` for the documented codes.
+        std::process::exit(e.exit_code());
     }
 }
 
-fn run() -> Result<(), YuchiError> {
-    let cli = Cli::parse();
+/// Parses the command line. `yuchi reset my password how do I` is a question rather than `reset`
+/// with stray words: when a subcommand gets words it doesn't take, they're read as `yuchi ask ...`.
+fn parse() -> Cli {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let error = match Cli::try_parse_from(&args) {
+        Ok(cli) => return cli,
+        Err(error) => error,
+    };
+    let stray_word = matches!(
+        error.get(ContextKind::InvalidArg),
+        Some(ContextValue::String(arg)) if !arg.starts_with('-')
+    );
+    let index = subcommand_index(&args);
+    if let (ErrorKind::UnknownArgument, true, Some(index)) = (error.kind(), stray_word, index) {
+        let mut question = args.clone();
+        question.insert(index, "ask".into());
+        if let Ok(cli) = Cli::try_parse_from(question) {
+            return cli;
+        }
+    }
+    // `yuchi config lsit` is more likely a typo than a question, so only point the way.
+    if let (ErrorKind::InvalidSubcommand, Some(index)) = (error.kind(), index) {
+        let _ = error.print();
+        eprintln!(
+            "\nTo ask a question that starts with `{}`, use `yuchi ask {} ...`.",
+            args[index].to_string_lossy(),
+            args[index].to_string_lossy()
+        );
+        std::process::exit(errors::exit_code::USAGE);
+    }
+    error.exit()
+}
 
-    // Handle non-AI flags
-    if cli.login {
-        commands::login()?;
-        return Ok(());
+/// Where the subcommand is in `args`: the first word that is neither an option nor an option's value.
+fn subcommand_index(args: &[OsString]) -> Option<usize> {
+    let command = Cli::command();
+    let takes_value: Vec<String> = command
+        .get_arguments()
+        .filter(|arg| arg.get_action().takes_values())
+        .filter_map(|arg| arg.get_long())
+        .map(|long| format!("--{}", long))
+        .collect();
+    let mut index = 1;
+    while let Some(arg) = args.get(index) {
+        let arg = arg.to_str()?;
+        if takes_value.iter().any(|option| option == arg) {
+            index += 2;
+        } else if arg.starts_with('-') {
+            index += 1;
+        } else {
+            return Some(index);
+        }
     }
-    if cli.logout {
-        commands::logout()?;
-        return Ok(());
+    None
+}
+
+fn run() -> Result<(), YuchiError> {
+    let cli = parse();
+    logging::init(cli.global.verbose, cli.global.trace_file.as_deref())?;
+    config::set_profile(cli.global.profile.as_deref())?;
+    session::set_session(cli.global.session.as_deref())?;
+    let mut flags = Vec::new();
+    if let Some(app_id) = cli.global.app_id {
+        flags.push(("app_id", "--app-id", app_id));
     }
-    if let Some(username) = cli.shape {
-        commands::set_shape(&username)?;
-        return Ok(());
+    if let Some(policy) = cli.global.tool_policy {
+        flags.push(("tool_policy", "--tool-policy", policy));
     }
-    if cli.sleep {
-        println!("Saving conversation state...");
-        return Ok(());
+    if !cli.global.context_file.is_empty() {
+        flags.push(("context_files", "--context-file", cli.global.context_file.join(",")));
     }
+    config::set_flags(flags);
+    let model = cli.global.model.as_deref();
 
-    // Handle AI-related flags and question
-    let prompt = if !cli.question.is_empty() {
-        cli.question.join(" ")
-    } else {
-        String::new()
+    let command = match cli.command {
+        Some(_) if cli.image.is_some() => {
+            return Err(YuchiError::Input(
+                "Pass --image after the subcommand, e.g. `yuchi ask --image <IMAGE_PATH> ...`.".to_string(),
+            ));
+        }
+        Some(command) => command,
+        None if !cli.question.is_empty() => Command::Ask {
+            image: cli.image,
+            question: cli.question,
+        },
+        None => {
+            Cli::command()
+                .print_help()
+                .map_err(|e| YuchiError::io("Failed to print help", e))?;
+            return Ok(());
+        }
     };
 
-    if cli.imagine {
-        let final_prompt = if prompt.is_empty() {
-            "!imagine".to_string()
-        } else {
-            format!("{} !imagine", prompt)
-        };
-        let response = commands::ask(&final_prompt, cli.model.as_deref(), cli.image.as_deref())?;
-        commands::download_image(&response)?;
-    } else if cli.reset {
-        commands::ask("!reset", cli.model.as_deref(), None)?;
-    } else if cli.wack {
-        commands::ask("!wack", cli.model.as_deref(), None)?;
-    } else if !prompt.is_empty() {
-        commands::ask(&prompt, cli.model.as_deref(), cli.image.as_deref())?;
-    } else {
-        display_help();
+    if matches!(command, Command::Ask { .. } | Command::Chat | Command::Imagine { .. }) {
+        session::allow_new();
+    }
+    match command {
+        Command::Ask { image, question } => {
+            commands::ask(&mut Context::load()?, &question.join(" "), model, image.as_deref())?;
+        }
+        Command::Chat => commands::chat(model)?,
+        Command::Login(args) => commands::login(&commands::LoginOptions {
+            api_key_env: args.api_key_env,
+            api_key_file: args.api_key_file,
+            api_key_stdin: args.api_key_stdin,
+            code: args.code,
+        })?,
+        Command::Logout => commands::logout()?,
+        Command::Whoami => commands::whoami()?,
+        Command::Doctor => doctor::run()?,
+        Command::Shape { username } => commands::set_shape(&username)?,
+        Command::Reset => {
+            commands::ask(&mut Context::load()?, "!reset", model, None)?;
+        }
+        Command::Wack => {
+            commands::ask(&mut Context::load()?, "!wack", model, None)?;
+        }
+        Command::Sleep => {
+            commands::ask(&mut Context::load()?, "!sleep", model, None)?;
+        }
+        Command::Imagine { image, prompt } => {
+            let final_prompt = if prompt.is_empty() {
+                "!imagine".to_string()
+            } else {
+                format!("{} !imagine", prompt.join(" "))
+            };
+            let mut context = Context::load()?;
+            let response = commands::ask(&mut context, &final_prompt, model, image.as_deref())?;
+            commands::download_image(&context, &response)?;
+        }
+        Command::Undo { force } => commands::undo(force)?,
+        Command::Unlock { ttl } => commands::unlock(ttl)?,
+        Command::Lock => commands::lock(),
+        Command::Config(command) => match command {
+            ConfigCommand::Get { key, reveal } => commands::config_get(&key, reveal)?,
+            ConfigCommand::Set { key, value } => commands::config_set(&key, &value)?,
+            ConfigCommand::Unset { key } => commands::config_unset(&key)?,
+            ConfigCommand::List { reveal, show_origin } => commands::config_list(reveal, show_origin)?,
+            ConfigCommand::Edit => commands::config_edit()?,
+            ConfigCommand::Validate => commands::config_validate()?,
+            ConfigCommand::Path => commands::config_path()?,
+        },
+        Command::Session(command) => match command {
+            SessionCommand::List => commands::session_list()?,
+            SessionCommand::Rename { old, new } => commands::session_rename(&old, &new)?,
+            SessionCommand::Fork { name, from } => commands::session_fork(&name, from.as_deref())?,
+            SessionCommand::Delete { name } => commands::session_delete(&name)?,
+        },
+        Command::VaultAgent => agent::serve()?,
     }
 
     Ok(())
@@ -0,0 +1,269 @@
+use crate::errors::YuchiError;
+use regex::Regex;
+
+/// One line of a hunk, without its `' '`, `-` or `+` marker.
+#[derive(Clone)]
+pub enum Line {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+#[derive(Clone)]
+pub struct Hunk {
+    /// The `@@ -a,b +c,d @@` line, shown when reviewing.
+    pub header: String,
+    /// 1-based line of the original file the hunk expects to start at; 0 for an empty file.
+    pub old_start: usize,
+    pub lines: Vec<Line>,
+    /// Set by `\ No newline at end of file` after an added or context line.
+    pub new_lacks_newline: bool,
+}
+
+impl Hunk {
+    fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Context(text) | Line::Remove(text) => Some(text.as_str()),
+                Line::Add(_) => None,
+            })
+            .collect()
+    }
+
+    fn new_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Context(text) | Line::Add(text) => Some(text.as_str()),
+                Line::Remove(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// The changes to one file. A missing `old_path` creates the file, a missing `new_path` deletes it.
+pub struct FilePatch {
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+impl FilePatch {
+    /// The path shown for this file.
+    pub fn path(&self) -> &str {
+        self.new_path.as_deref().or(self.old_path.as_deref()).unwrap_or_default()
+    }
+}
+
+/// Splits a unified diff, possibly covering several files, into per-file hunks.
+/// Hunk line counts are not trusted; a hunk runs until the next hunk or file header.
+pub fn parse(diff: &str) -> Result<Vec<FilePatch>, YuchiError> {
+    let hunk_header = Regex::new(r"^@@ -(\d+)(?:,\d+)? \+\d+(?:,\d+)? @@")
+        .map_err(|e| YuchiError::Tool(format!("Failed to compile regex: {}", e)))?;
+    let lines: Vec<&str> = diff.lines().collect();
+    let mut files: Vec<FilePatch> = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i];
+        if line.starts_with("--- ") && lines.get(i + 1).is_some_and(|next| next.starts_with("+++ ")) {
+            files.push(FilePatch {
+                old_path: header_path(&line[4..]),
+                new_path: header_path(&lines[i + 1][4..]),
+                hunks: Vec::new(),
+            });
+            i += 2;
+            continue;
+        }
+        if let Some(captures) = hunk_header.captures(line) {
+            let file = files.last_mut().ok_or_else(|| {
+                YuchiError::Tool("The patch has a hunk before any '--- a/path' / '+++ b/path' header.".to_string())
+            })?;
+            let mut hunk = Hunk {
+                header: line.to_string(),
+                old_start: captures[1].parse().unwrap_or(0),
+                lines: Vec::new(),
+                new_lacks_newline: false,
+            };
+            i += 1;
+            while let Some(&body) = lines.get(i) {
+                let next_file = body.starts_with("--- ") && lines.get(i + 1).is_some_and(|l| l.starts_with("+++ "));
+                if next_file || body.starts_with("@@") || body.starts_with("diff ") {
+                    break;
+                }
+                if body.starts_with('\\') {
+                    if matches!(hunk.lines.last(), Some(Line::Add(_)) | Some(Line::Context(_))) {
+                        hunk.new_lacks_newline = true;
+                    }
+                } else if let Some(text) = body.strip_prefix('+') {
+                    hunk.lines.push(Line::Add(text.to_string()));
+                } else if let Some(text) = body.strip_prefix('-') {
+                    hunk.lines.push(Line::Remove(text.to_string()));
+                } else {
+                    // Blank context lines often lose their leading space on the way.
+                    hunk.lines.push(Line::Context(body.strip_prefix(' ').unwrap_or(body).to_string()));
+                }
+                i += 1;
+            }
+            // Trailing blank lines between files aren't part of the hunk.
+            while matches!(hunk.lines.last(), Some(Line::Context(text)) if text.is_empty()) {
+                hunk.lines.pop();
+            }
+            file.hunks.push(hunk);
+            continue;
+        }
+        i += 1;
+    }
+
+    if let Some(file) = files.iter().find(|file| file.hunks.is_empty() && file.old_path.is_some()) {
+        return Err(YuchiError::Tool(format!("The patch for '{}' has no hunks.", file.path())));
+    }
+    Ok(files)
+}
+
+/// `a/src/main.rs\t2024-01-01` is `src/main.rs`; `/dev/null` is no file.
+fn header_path(raw: &str) -> Option<String> {
+    let path = raw.split('\t').next().unwrap_or(raw).trim();
+    if path == "/dev/null" {
+        return None;
+    }
+    let path = path.strip_prefix("a/").or_else(|| path.strip_prefix("b/")).unwrap_or(path);
+    Some(path.to_string())
+}
+
+/// Applies `hunks` to `original` in order. Each hunk is matched where its header says or,
+/// if the file moved on since, at the nearest place its context and removed lines match.
+pub fn apply(original: &str, hunks: &[&Hunk]) -> Result<String, String> {
+    let lines: Vec<&str> = original.lines().collect();
+    let mut output: Vec<&str> = Vec::new();
+    let mut cursor = 0;
+    let mut ends_with_newline = original.is_empty() || original.ends_with('\n');
+
+    for hunk in hunks {
+        let old = hunk.old_lines();
+        let expected = hunk.old_start.saturating_sub(1).max(cursor);
+        let position = (cursor..=lines.len().saturating_sub(old.len()))
+            .filter(|&start| start + old.len() <= lines.len() && lines[start..start + old.len()] == old[..])
+            .min_by_key(|&start| start.abs_diff(expected))
+            .ok_or_else(|| format!("hunk {} does not match the file", hunk.header))?;
+
+        output.extend_from_slice(&lines[cursor..position]);
+        output.extend(hunk.new_lines());
+        cursor = position + old.len();
+        if cursor == lines.len() {
+            ends_with_newline = !hunk.new_lacks_newline;
+        }
+    }
+    output.extend_from_slice(&lines[cursor..]);
+
+    let mut text = output.join("\n");
+    if ends_with_newline && !output.is_empty() {
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply_all(original: &str, diff: &str) -> Result<String, String> {
+        let files = parse(diff).unwrap();
+        let hunks: Vec<&Hunk> = files[0].hunks.iter().collect();
+        apply(original, &hunks)
+    }
+
+    #[test]
+    fn parses_several_files() {
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs\n\
+                    --- a/src/lib.rs\t2024-01-01 10:00:00\n\
+                    +++ b/src/lib.rs\n\
+                    @@ -1,2 +1,2 @@\n fn a() {}\n-fn b() {}\n+fn c() {}\n\
+                    @@ -10 +10 @@\n-x\n+y\n\
+                    \n\
+                    --- /dev/null\n\
+                    +++ b/NOTES.md\n\
+                    @@ -0,0 +1 @@\n+notes\n\
+                    --- a/old.txt\n\
+                    +++ /dev/null\n\
+                    @@ -1 +0,0 @@\n-gone\n";
+        let files = parse(diff).unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0].old_path.as_deref(), Some("src/lib.rs"));
+        assert_eq!(files[0].new_path.as_deref(), Some("src/lib.rs"));
+        assert_eq!(files[0].hunks.len(), 2);
+        assert_eq!(files[0].hunks[0].old_start, 1);
+        assert_eq!(files[0].hunks[0].lines.len(), 3);
+        assert_eq!(files[0].hunks[1].old_start, 10);
+        assert_eq!(files[0].hunks[1].lines.len(), 2, "the blank line between files isn't context");
+        assert_eq!(files[1].old_path, None);
+        assert_eq!(files[1].path(), "NOTES.md");
+        assert_eq!(files[2].new_path, None);
+        assert_eq!(files[2].path(), "old.txt");
+    }
+
+    #[test]
+    fn parses_a_rename() {
+        let files = parse("--- a/old/name.rs\n+++ b/new/name.rs\n@@ -1 +1 @@\n-a\n+b\n").unwrap();
+        assert_eq!(files[0].old_path.as_deref(), Some("old/name.rs"));
+        assert_eq!(files[0].new_path.as_deref(), Some("new/name.rs"));
+        assert_eq!(files[0].path(), "new/name.rs");
+    }
+
+    #[test]
+    fn rejects_malformed_patches() {
+        assert!(parse("@@ -1 +1 @@\n-a\n+b\n").is_err());
+        assert!(parse("--- a/x\n+++ b/x\n").is_err());
+        assert!(parse("just some text").unwrap().is_empty());
+    }
+
+    #[test]
+    fn applies_where_the_header_says() {
+        let original = "one\ntwo\nthree\n";
+        let result = apply_all(original, "--- a/f\n+++ b/f\n@@ -2 +2 @@\n-two\n+2\n").unwrap();
+        assert_eq!(result, "one\n2\nthree\n");
+    }
+
+    #[test]
+    fn finds_a_hunk_that_moved() {
+        let original = "new\nlines\nabove\none\ntwo\nthree\n";
+        let result = apply_all(original, "--- a/f\n+++ b/f\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n").unwrap();
+        assert_eq!(result, "new\nlines\nabove\none\n2\nthree\n");
+    }
+
+    #[test]
+    fn picks_the_nearest_of_several_matches() {
+        let original = "x\nend\nx\nend\nx\nend\n";
+        let result = apply_all(original, "--- a/f\n+++ b/f\n@@ -4,2 +4,2 @@\n-x\n+y\n end\n").unwrap();
+        assert_eq!(result, "x\nend\ny\nend\nx\nend\n");
+    }
+
+    #[test]
+    fn applies_hunks_in_order() {
+        let original = "a\nb\nc\nd\n";
+        let diff = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n+A\n@@ -4 +4,2 @@\n d\n+e\n";
+        assert_eq!(apply_all(original, diff).unwrap(), "A\nb\nc\nd\ne\n");
+    }
+
+    #[test]
+    fn fails_when_a_hunk_does_not_match() {
+        let error = apply_all("one\ntwo\n", "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-three\n+3\n").unwrap_err();
+        assert!(error.contains("does not match"), "{}", error);
+    }
+
+    #[test]
+    fn creates_and_deletes_files() {
+        assert_eq!(apply_all("", "--- /dev/null\n+++ b/f\n@@ -0,0 +1,2 @@\n+a\n+b\n").unwrap(), "a\nb\n");
+        assert_eq!(apply_all("a\nb\n", "--- a/f\n+++ /dev/null\n@@ -1,2 +0,0 @@\n-a\n-b\n").unwrap(), "");
+    }
+
+    #[test]
+    fn keeps_track_of_the_final_newline() {
+        let diff = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n+b\n\\ No newline at end of file\n";
+        assert_eq!(apply_all("a\n", diff).unwrap(), "b");
+        let diff = "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n\\ No newline at end of file\n+b\n";
+        assert_eq!(apply_all("a", diff).unwrap(), "b\n");
+        assert_eq!(apply_all("a\nz", "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n+b\n").unwrap(), "b\nz");
+    }
+}
@@ -0,0 +1,211 @@
+use std::process::{Command, ExitStatus, Output, Stdio};
+
+/// Variables tool commands keep when `tool_env_allow` isn't set. A trailing `*` matches any suffix.
+pub const DEFAULT_ENV_ALLOW: &[&str] = &[
+    "PATH", "HOME", "USER", "LOGNAME", "SHELL", "TERM", "COLORTERM", "NO_COLOR", "LANG", "LANGUAGE", "LC_*", "TZ",
+    "TMPDIR",
+];
+
+/// The environment tool commands start with: only the allowed variables of ours, plus `set`.
+#[derive(Clone, Debug)]
+pub struct ToolEnv {
+    pub allow: Vec<String>,
+    pub set: Vec<(String, String)>,
+}
+
+impl Default for ToolEnv {
+    fn default() -> Self {
+        ToolEnv { allow: DEFAULT_ENV_ALLOW.iter().map(|name| name.to_string()).collect(), set: Vec::new() }
+    }
+}
+
+impl ToolEnv {
+    fn allows(&self, name: &str) -> bool {
+        self.allow.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        })
+    }
+}
+
+/// Resource limits for each tool command; `None` leaves a limit as inherited.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub cpu_seconds: Option<u64>,
+    pub memory_mb: Option<u64>,
+    pub file_size_mb: Option<u64>,
+    /// Counted over every process the user owns (`RLIMIT_NPROC`), not only the command's.
+    pub processes: Option<u64>,
+}
+
+/// Runs `command` to completion with `env` and `limits`, capturing its output. The command gets a
+/// process group of its own, which is killed when it's done, on error, or when the user hits Ctrl-C.
+pub fn output(mut command: Command, env: &ToolEnv, limits: &Limits) -> Result<Output, std::io::Error> {
+    command.env_clear();
+    command.envs(std::env::vars_os().filter(|(name, _)| name.to_str().is_some_and(|name| env.allows(name))));
+    command.envs(env.set.iter().map(|(name, value)| (name, value)));
+    command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    platform::output(command, *limits)
+}
+
+/// Whether the last command was stopped by Ctrl-C; reading it resets it.
+pub fn take_interrupted() -> bool {
+    platform::take_interrupted()
+}
+
+/// Why a command that didn't succeed stopped, when a signal or a limit explains it.
+pub fn describe_failure(status: &ExitStatus) -> Option<String> {
+    platform::describe_failure(status)
+}
+
+#[cfg(unix)]
+mod platform {
+    use super::Limits;
+    use crate::errors::exit_code;
+    use std::io;
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    use std::process::{Command, ExitStatus, Output};
+    use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+    use std::sync::Once;
+
+    /// The process group of the command running now, or 0.
+    static RUNNING: AtomicI32 = AtomicI32::new(0);
+    static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+    static HANDLER: Once = Once::new();
+
+    /// Kills the group when dropped, taking along anything the command left running.
+    struct Group(i32);
+
+    impl Drop for Group {
+        fn drop(&mut self) {
+            RUNNING.store(0, Ordering::SeqCst);
+            // SAFETY: kill has no memory effects; a group that's already gone just yields ESRCH.
+            unsafe { libc::kill(-self.0, libc::SIGKILL) };
+        }
+    }
+
+    pub fn output(mut command: Command, limits: Limits) -> io::Result<Output> {
+        install_handler();
+        // Ctrl-C goes to the terminal's foreground group, which is then us but not the command.
+        command.process_group(0);
+        // SAFETY: the hook only makes raw system calls on values copied into it.
+        unsafe {
+            command.pre_exec(move || {
+                // SIGXCPU at the soft limit tells why the command stopped; the hard one is the backstop.
+                set_limit(libc::RLIMIT_CPU, limits.cpu_seconds, 1)?;
+                set_limit(libc::RLIMIT_AS, limits.memory_mb.map(|mb| mb.saturating_mul(1024 * 1024)), 0)?;
+                set_limit(libc::RLIMIT_FSIZE, limits.file_size_mb.map(|mb| mb.saturating_mul(1024 * 1024)), 0)?;
+                set_limit(libc::RLIMIT_NPROC, limits.processes, 0)
+            });
+        }
+        let child = command.spawn()?;
+        let group = Group(child.id() as i32);
+        RUNNING.store(group.0, Ordering::SeqCst);
+        let output = child.wait_with_output();
+        drop(group);
+        output
+    }
+
+    #[cfg(target_os = "linux")]
+    type Resource = libc::__rlimit_resource_t;
+    #[cfg(not(target_os = "linux"))]
+    type Resource = libc::c_int;
+
+    /// Sets the soft limit to `value` and the hard limit `slack` above it, never above the
+    /// inherited hard limit, which only root could raise.
+    fn set_limit(resource: Resource, value: Option<u64>, slack: u64) -> io::Result<()> {
+        let Some(value) = value else { return Ok(()) };
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        // SAFETY: `limit` outlives both calls.
+        if unsafe { libc::getrlimit(resource, &mut limit) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        limit.rlim_max = limit.rlim_max.min(value.saturating_add(slack) as libc::rlim_t);
+        limit.rlim_cur = limit.rlim_max.min(value as libc::rlim_t);
+        if unsafe { libc::setrlimit(resource, &limit) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// While a command runs, Ctrl-C kills its group and cancels the question; otherwise it exits
+    /// the way it would without a handler.
+    fn install_handler() {
+        HANDLER.call_once(|| {
+            let result = ctrlc::set_handler(|| match RUNNING.load(Ordering::SeqCst) {
+                0 => std::process::exit(exit_code::CANCELLED),
+                group => {
+                    INTERRUPTED.store(true, Ordering::SeqCst);
+                    // SAFETY: as in `Group::drop`.
+                    unsafe { libc::kill(-group, libc::SIGKILL) };
+                }
+            });
+            if let Err(e) = result {
+                log::warn!("can't handle Ctrl-C for tool commands: {}", e);
+            }
+        });
+    }
+
+    pub fn take_interrupted() -> bool {
+        INTERRUPTED.swap(false, Ordering::SeqCst)
+    }
+
+    pub fn describe_failure(status: &ExitStatus) -> Option<String> {
+        let signal = status.signal()?;
+        Some(match signal {
+            libc::SIGXCPU => "it ran out of CPU time (tool_cpu_seconds)".to_string(),
+            libc::SIGXFSZ => "it wrote a file larger than tool_file_size_mb".to_string(),
+            libc::SIGKILL => "it was killed".to_string(),
+            libc::SIGSEGV | libc::SIGABRT => {
+                format!("it crashed with signal {}, possibly out of memory (tool_memory_mb)", signal)
+            }
+            _ => format!("it was stopped by signal {}", signal),
+        })
+    }
+}
+
+#[cfg(not(unix))]
+mod platform {
+    use super::Limits;
+    use std::io;
+    use std::process::{Command, ExitStatus, Output};
+
+    pub fn output(mut command: Command, limits: Limits) -> io::Result<Output> {
+        if limits.cpu_seconds.or(limits.memory_mb).or(limits.file_size_mb).or(limits.processes).is_some() {
+            log::warn!("resource limits for tool commands are only applied on Unix");
+        }
+        command.output()
+    }
+
+    pub fn take_interrupted() -> bool {
+        false
+    }
+
+    pub fn describe_failure(_status: &ExitStatus) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_env_keeps_only_the_basics() {
+        let env = ToolEnv::default();
+        assert!(env.allows("PATH"));
+        assert!(env.allows("LC_ALL"));
+        assert!(!env.allows("LC"));
+        assert!(!env.allows("PATHEXT"));
+        assert!(!env.allows("AWS_SECRET_ACCESS_KEY"));
+    }
+
+    #[test]
+    fn star_matches_any_suffix() {
+        let env = ToolEnv { allow: vec!["CARGO_*".to_string(), "*".to_string()], set: Vec::new() };
+        assert!(env.allows("CARGO_HOME"));
+        assert!(env.allows("ANYTHING"));
+        let env = ToolEnv { allow: vec!["CARGO_*".to_string()], set: Vec::new() };
+        assert!(!env.allows("CARGO"));
+    }
+}
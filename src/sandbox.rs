@@ -0,0 +1,261 @@
+use crate::errors::YuchiError;
+use std::path::Path;
+use std::process::Command;
+
+/// Where tool commands run. Sandboxed commands see the project directory read-write, the rest
+/// of the filesystem read-only, a private `/tmp`, and no network.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Sandbox {
+    Off,
+    /// `bwrap` from bubblewrap.
+    Bubblewrap,
+    /// User, mount and network namespaces set up by yuchi itself (Linux only).
+    Namespaces,
+}
+
+impl Sandbox {
+    /// Resolves the `sandbox` setting; `auto` prefers bubblewrap and falls back to namespaces.
+    pub fn from_setting(value: Option<&str>) -> Sandbox {
+        match value {
+            Some("bwrap") => Sandbox::Bubblewrap,
+            Some("namespaces") => Sandbox::Namespaces,
+            Some("auto") if bwrap_installed() => Sandbox::Bubblewrap,
+            Some("auto") if cfg!(target_os = "linux") => Sandbox::Namespaces,
+            Some("auto") => {
+                log::warn!("no sandbox is available on this platform; tool commands run unsandboxed");
+                Sandbox::Off
+            }
+            _ => Sandbox::Off,
+        }
+    }
+
+    /// Shown in confirmation prompts.
+    pub fn describe(self) -> &'static str {
+        match self {
+            Sandbox::Off => "no sandbox",
+            Sandbox::Bubblewrap => "bwrap sandbox: project writable, no network",
+            Sandbox::Namespaces => "namespace sandbox: project writable, no network",
+        }
+    }
+
+    /// A command that runs `program` with `args` inside this sandbox, with `root` writable.
+    pub fn command(self, program: &str, args: &[String], root: &Path) -> Result<Command, YuchiError> {
+        match self {
+            Sandbox::Off => {
+                let mut command = Command::new(program);
+                command.args(args);
+                Ok(command)
+            }
+            Sandbox::Bubblewrap => {
+                let cwd = std::env::current_dir()
+                    .map_err(|e| YuchiError::io("Failed to read the current directory", e))?;
+                let mut command = Command::new("bwrap");
+                command.args(["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc"]);
+                if private_tmp(root, &cwd) {
+                    command.args(["--tmpfs", "/tmp"]);
+                }
+                command
+                    .arg("--bind")
+                    .arg(root)
+                    .arg(root)
+                    .args(["--unshare-all", "--new-session", "--die-with-parent", "--chdir"])
+                    .arg(cwd)
+                    .arg("--")
+                    .arg(program)
+                    .args(args);
+                Ok(command)
+            }
+            Sandbox::Namespaces => namespaces::command(program, args, root),
+        }
+    }
+
+    /// Turns a failure to start a sandboxed command into an error that points at the setting.
+    pub fn spawn_error(self, command: &str, error: std::io::Error) -> YuchiError {
+        match self {
+            Sandbox::Bubblewrap if error.kind() == std::io::ErrorKind::NotFound => YuchiError::Config(
+                "The sandbox is set to `bwrap` but bubblewrap isn't installed. Install it, or run \
+                 `yuchi config set sandbox namespaces` (or `off`)."
+                    .to_string(),
+            ),
+            Sandbox::Namespaces if error.kind() != std::io::ErrorKind::NotFound => YuchiError::Config(format!(
+                "The namespace sandbox couldn't start `{}`: {}. Unprivileged user namespaces may be disabled; \
+                 try `yuchi config set sandbox bwrap` or `off`.",
+                command, error
+            )),
+            _ => YuchiError::Tool(format!("Failed to execute `{}`: {}", command, error)),
+        }
+    }
+}
+
+/// Whether commands get an empty `/tmp` of their own. Not when the project or the working
+/// directory is in `/tmp`, which the empty one would hide; `/tmp` stays read-only then.
+fn private_tmp(root: &Path, cwd: &Path) -> bool {
+    !root.starts_with("/tmp") && !cwd.starts_with("/tmp")
+}
+
+fn bwrap_installed() -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join("bwrap").is_file()))
+}
+
+#[cfg(target_os = "linux")]
+mod namespaces {
+    use crate::errors::YuchiError;
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::process::CommandExt;
+    use std::path::Path;
+    use std::process::Command;
+
+    /// Everything the child needs, prepared before the fork: between fork and exec only
+    /// async-signal-safe calls are allowed, so nothing in there may allocate.
+    struct Setup {
+        root: CString,
+        /// The directory the command runs in, entered again once the mounts are in place.
+        cwd: CString,
+        private_tmp: bool,
+        uid_map: CString,
+        gid_map: CString,
+    }
+
+    pub fn command(program: &str, args: &[String], root: &Path) -> Result<Command, YuchiError> {
+        let cwd = std::env::current_dir().map_err(|e| YuchiError::io("Failed to read the current directory", e))?;
+        let cstring = |bytes: &[u8]| {
+            CString::new(bytes).map_err(|_| YuchiError::Tool(format!("'{}' contains a NUL byte", root.display())))
+        };
+        // SAFETY: getuid and getgid can't fail.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let setup = Setup {
+            root: cstring(root.as_os_str().as_bytes())?,
+            cwd: cstring(cwd.as_os_str().as_bytes())?,
+            private_tmp: super::private_tmp(root, &cwd),
+            uid_map: cstring(format!("{} {} 1", uid, uid).as_bytes())?,
+            gid_map: cstring(format!("{} {} 1", gid, gid).as_bytes())?,
+        };
+
+        let mut command = Command::new(program);
+        command.args(args);
+        // SAFETY: the hook only makes raw system calls on data prepared above.
+        unsafe {
+            command.pre_exec(move || enter(&setup));
+        }
+        Ok(command)
+    }
+
+    fn enter(setup: &Setup) -> io::Result<()> {
+        check(unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWNET) })?;
+        // Kernels before 3.19 have no setgroups file; the gid map works without it there.
+        match write(c"/proc/self/setgroups", c"deny") {
+            Err(e) if e.raw_os_error() != Some(libc::ENOENT) => return Err(e),
+            _ => {}
+        }
+        write(c"/proc/self/uid_map", &setup.uid_map)?;
+        write(c"/proc/self/gid_map", &setup.gid_map)?;
+
+        // Keep the mounts below from leaking back into the parent namespace.
+        mount(None, c"/", None, libc::MS_REC | libc::MS_PRIVATE)?;
+        // A mount of its own, so the project can stay writable when everything else isn't.
+        mount(Some(&setup.root), &setup.root, None, libc::MS_BIND | libc::MS_REC)?;
+        if setup.private_tmp {
+            mount(Some(c"tmpfs"), c"/tmp", Some(c"tmpfs"), 0)?;
+        }
+
+        set_read_only(c"/", true)?;
+        set_read_only(&setup.root, false)?;
+        if setup.private_tmp {
+            set_read_only(c"/tmp", false)?;
+        }
+        // The old working directory is on the mounts from before; resolve it again on the new ones,
+        // as `bwrap --chdir` does.
+        check(unsafe { libc::chdir(setup.cwd.as_ptr()) })
+    }
+
+    fn check(result: libc::c_int) -> io::Result<()> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write(path: &std::ffi::CStr, content: &std::ffi::CStr) -> io::Result<()> {
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+        check(fd)?;
+        let bytes = content.to_bytes();
+        let written = unsafe { libc::write(fd, bytes.as_ptr().cast(), bytes.len()) };
+        let error = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        if written != bytes.len() as isize {
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    fn mount(
+        source: Option<&std::ffi::CStr>,
+        target: &std::ffi::CStr,
+        fstype: Option<&std::ffi::CStr>,
+        flags: libc::c_ulong,
+    ) -> io::Result<()> {
+        let source = source.map_or(std::ptr::null(), |s| s.as_ptr());
+        let fstype = fstype.map_or(std::ptr::null(), |s| s.as_ptr());
+        check(unsafe { libc::mount(source, target.as_ptr(), fstype, flags, std::ptr::null()) })
+    }
+
+    /// Sets or clears read-only on `path` and every mount below it. Unlike a read-only remount,
+    /// `mount_setattr` (Linux 5.12) reaches the submounts too.
+    fn set_read_only(path: &std::ffi::CStr, read_only: bool) -> io::Result<()> {
+        let mut attr: libc::mount_attr = unsafe { std::mem::zeroed() };
+        if read_only {
+            attr.attr_set = libc::MOUNT_ATTR_RDONLY;
+        } else {
+            attr.attr_clr = libc::MOUNT_ATTR_RDONLY;
+        }
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_mount_setattr,
+                libc::AT_FDCWD,
+                path.as_ptr(),
+                libc::AT_RECURSIVE,
+                &mut attr as *mut libc::mount_attr,
+                std::mem::size_of::<libc::mount_attr>(),
+            )
+        };
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod namespaces {
+    use crate::errors::YuchiError;
+    use std::path::Path;
+    use std::process::Command;
+
+    pub fn command(_program: &str, _args: &[String], _root: &Path) -> Result<Command, YuchiError> {
+        Err(YuchiError::Config(
+            "The namespace sandbox only works on Linux. Run `yuchi config set sandbox bwrap` or `off`.".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tmp_is_private_outside_tmp() {
+        assert!(private_tmp(Path::new("/home/me/project"), Path::new("/home/me/project/src")));
+        assert!(private_tmp(Path::new("/tmpfiles/project"), Path::new("/tmpfiles/project")));
+    }
+
+    #[test]
+    fn tmp_stays_shared_for_work_under_tmp() {
+        assert!(!private_tmp(Path::new("/tmp/project"), Path::new("/tmp/project/src")));
+        assert!(!private_tmp(Path::new("/home/me/project"), Path::new("/tmp")));
+    }
+}
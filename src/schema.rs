@@ -0,0 +1,202 @@
+use serde_json::Value;
+
+/// How problems name the arguments object itself; its properties are named on their own.
+const ARGUMENTS: &str = "arguments";
+
+/// Checks `value` against the parts of JSON schema that tool definitions use: `type`, `properties`,
+/// `required`, `additionalProperties`, `enum`, `items`, `minimum`/`maximum` and
+/// `minLength`/`maxLength`. Returns every problem found, each naming where it is.
+pub fn validate(schema: &Value, value: &Value) -> Vec<String> {
+    let mut problems = Vec::new();
+    check(schema, value, ARGUMENTS, &mut problems);
+    problems
+}
+
+fn check(schema: &Value, value: &Value, at: &str, problems: &mut Vec<String>) {
+    if let Some(expected) = schema.get("type") {
+        let types: Vec<&str> = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|name| has_type(value, name)) {
+            let expected: Vec<String> = types.iter().map(|name| with_article(name)).collect();
+            problems.push(format!("`{}` must be {}, not {}", at, expected.join(" or "), type_name(value)));
+            return;
+        }
+    }
+
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            let allowed: Vec<String> = allowed.iter().map(Value::to_string).collect();
+            problems.push(format!("`{}` must be one of {}", at, allowed.join(", ")));
+        }
+    }
+
+    match value {
+        Value::Object(map) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for name in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+                if let Some(name) = name.as_str() {
+                    if map.get(name).is_none_or(Value::is_null) {
+                        problems.push(format!("`{}` is missing the required `{}`", at, name));
+                    }
+                }
+            }
+            for (name, item) in map {
+                match properties.and_then(|p| p.get(name)) {
+                    Some(property) if at == ARGUMENTS => check(property, item, name, problems),
+                    Some(property) => check(property, item, &format!("{}.{}", at, name), problems),
+                    None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                        problems.push(format!("`{}` has an unknown property `{}`", at, name));
+                    }
+                    None => {}
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    check(item_schema, item, &format!("{}[{}]", at, index), problems);
+                }
+            }
+        }
+        Value::String(text) => {
+            let length = text.chars().count() as u64;
+            if schema.get("minLength").and_then(Value::as_u64).is_some_and(|min| length < min) {
+                problems.push(format!("`{}` is shorter than {} characters", at, schema["minLength"]));
+            }
+            if schema.get("maxLength").and_then(Value::as_u64).is_some_and(|max| length > max) {
+                problems.push(format!("`{}` is longer than {} characters", at, schema["maxLength"]));
+            }
+        }
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            if schema.get("minimum").and_then(Value::as_f64).is_some_and(|min| number < min) {
+                problems.push(format!("`{}` must be at least {}", at, schema["minimum"]));
+            }
+            if schema.get("maximum").and_then(Value::as_f64).is_some_and(|max| number > max) {
+                problems.push(format!("`{}` must be at most {}", at, schema["maximum"]));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        // Types this validator doesn't know aren't held against the value.
+        _ => true,
+    }
+}
+
+fn with_article(name: &str) -> String {
+    match name {
+        "null" => name.to_string(),
+        "object" | "array" | "integer" => format!("an {}", name),
+        _ => format!("a {}", name),
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Object(_) => "an object",
+        Value::Array(_) => "an array",
+        Value::String(_) => "a string",
+        Value::Bool(_) => "a boolean",
+        Value::Null => "null",
+        Value::Number(_) => "a number",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["path"],
+            "additionalProperties": false,
+            "properties": {
+                "path": { "type": "string", "minLength": 1, "maxLength": 10 },
+                "mode": { "type": "string", "enum": ["read", "write"] },
+                "limit": { "type": "integer", "minimum": 1, "maximum": 100 },
+                "tags": { "type": "array", "items": { "type": "string" } },
+                "note": { "type": ["string", "null"] }
+            }
+        })
+    }
+
+    #[test]
+    fn accepts_valid_arguments() {
+        let arguments = json!({ "path": "src", "mode": "read", "limit": 5, "tags": ["a", "b"], "note": null });
+        assert_eq!(validate(&schema(), &arguments), Vec::<String>::new());
+        assert!(validate(&schema(), &json!({ "path": "src", "limit": 5.0 })).is_empty());
+    }
+
+    #[test]
+    fn reports_missing_and_unknown_properties() {
+        let problems = validate(&schema(), &json!({ "colour": "red" }));
+        assert_eq!(
+            problems,
+            ["`arguments` is missing the required `path`", "`arguments` has an unknown property `colour`"]
+        );
+        let problems = validate(&schema(), &json!({ "path": null }));
+        assert!(problems.contains(&"`arguments` is missing the required `path`".to_string()), "{:?}", problems);
+    }
+
+    #[test]
+    fn reports_wrong_types() {
+        assert_eq!(validate(&schema(), &json!("src")), ["`arguments` must be an object, not a string"]);
+        assert_eq!(validate(&schema(), &json!({ "path": 3 })), ["`path` must be a string, not a number"]);
+        let problems = validate(&schema(), &json!({ "path": "a", "limit": 2.5 }));
+        assert_eq!(problems, ["`limit` must be an integer, not a number"]);
+        let problems = validate(&schema(), &json!({ "path": "a", "note": 1 }));
+        assert_eq!(problems, ["`note` must be a string or null, not a number"]);
+        let problems = validate(&schema(), &json!({ "path": "a", "tags": ["x", 1] }));
+        assert_eq!(problems, ["`tags[1]` must be a string, not a number"]);
+    }
+
+    #[test]
+    fn names_nested_properties_by_their_path() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "edits": {
+                    "type": "array",
+                    "items": { "type": "object", "required": ["line"], "properties": { "line": { "type": "integer" } } }
+                }
+            }
+        });
+        let problems = validate(&schema, &json!({ "edits": [{ "line": 1 }, { "line": "two" }] }));
+        assert_eq!(problems, ["`edits[1].line` must be an integer, not a string"]);
+        let problems = validate(&schema, &json!({ "edits": [{}] }));
+        assert_eq!(problems, ["`edits[0]` is missing the required `line`"]);
+    }
+
+    #[test]
+    fn reports_values_out_of_range() {
+        let problems = validate(&schema(), &json!({ "path": "a", "mode": "exec" }));
+        assert_eq!(problems, ["`mode` must be one of \"read\", \"write\""]);
+        assert_eq!(validate(&schema(), &json!({ "path": "" })), ["`path` is shorter than 1 characters"]);
+        assert_eq!(validate(&schema(), &json!({ "path": "abcdefghijk" })), ["`path` is longer than 10 characters"]);
+        assert_eq!(validate(&schema(), &json!({ "path": "a", "limit": 0 })), ["`limit` must be at least 1"]);
+        assert_eq!(validate(&schema(), &json!({ "path": "a", "limit": 101 })), ["`limit` must be at most 100"]);
+    }
+
+    #[test]
+    fn ignores_what_it_does_not_know() {
+        let schema = json!({ "type": "object", "properties": { "when": { "type": "date", "format": "iso" } } });
+        assert!(validate(&schema, &json!({ "when": "today", "extra": true })).is_empty());
+        assert!(validate(&json!({}), &json!([1, "two"])).is_empty());
+    }
+}
@@ -0,0 +1,254 @@
+use crate::config::{self, Config, Origin};
+use crate::errors::YuchiError;
+use crate::vault;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Session that talks in the profile's own `channel_id`.
+pub const DEFAULT_SESSION: &str = "default";
+
+/// Exchanges kept per session, enough to summarize it for `yuchi session fork`.
+const MAX_TRANSCRIPT: usize = 20;
+
+static SESSION: OnceLock<String> = OnceLock::new();
+/// Whether this run may start a session that doesn't exist yet; only commands that talk to the shape do.
+static STARTS_SESSIONS: AtomicBool = AtomicBool::new(false);
+
+/// Selects a named session for this run: `name`, then `YUCHI_SESSION`. Only the first call has an effect.
+pub fn set_session(name: Option<&str>) -> Result<(), YuchiError> {
+    let name = name
+        .map(str::to_string)
+        .or_else(|| env::var("YUCHI_SESSION").ok().filter(|v| !v.trim().is_empty()));
+    if let Some(name) = name {
+        check_name(&name)?;
+        let _ = SESSION.set(name);
+    }
+    Ok(())
+}
+
+/// Lets this run start the session it selects if there is none of that name yet.
+pub fn allow_new() {
+    STARTS_SESSIONS.store(true, Ordering::SeqCst);
+}
+
+pub fn check_name(name: &str) -> Result<(), YuchiError> {
+    if config::is_valid_name(name) {
+        return Ok(());
+    }
+    Err(YuchiError::Input(format!(
+        "Invalid session name '{}'. Use letters, digits, '-' and '_'.",
+        name
+    )))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Exchange {
+    pub question: String,
+    pub reply: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Session {
+    pub channel_id: String,
+    /// Git repository this session was created for in project mode.
+    #[serde(default)]
+    pub project_root: Option<PathBuf>,
+    pub created_at: u64,
+    pub last_used: u64,
+    /// The latest exchanges, oldest first.
+    #[serde(default)]
+    pub transcript: Vec<Exchange>,
+}
+
+impl Session {
+    pub fn new(channel_id: String, project_root: Option<PathBuf>) -> Self {
+        let now = now();
+        Session {
+            channel_id,
+            project_root,
+            created_at: now,
+            last_used: now,
+            transcript: Vec::new(),
+        }
+    }
+}
+
+/// The sessions of one profile, kept next to its config file.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Store {
+    #[serde(default)]
+    pub sessions: BTreeMap<String, Session>,
+}
+
+impl Store {
+    pub fn path() -> Result<PathBuf, YuchiError> {
+        let file_name = match config::profile() {
+            config::DEFAULT_PROFILE => "sessions.json".to_string(),
+            other => format!("sessions-{}.json", other),
+        };
+        Ok(Config::path()?.with_file_name(file_name))
+    }
+
+    pub fn load() -> Result<Self, YuchiError> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Store::default());
+        }
+        let raw = fs::read_to_string(&path)
+            .map_err(|e| YuchiError::io(format!("Failed to read sessions '{}'", path.display()), e))?;
+        serde_json::from_str(&raw)
+            .map_err(|e| YuchiError::json(format!("Sessions file '{}' is corrupted", path.display()), e))
+    }
+
+    /// Writes the store; transcripts are private, so the file is too.
+    pub fn save(&self) -> Result<(), YuchiError> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| YuchiError::json("Failed to serialize sessions", e))?;
+        vault::write_private(&Self::path()?, json.as_bytes())
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Session, YuchiError> {
+        self.sessions
+            .get(name)
+            .ok_or_else(|| YuchiError::Input(format!("No session named '{}'. See `yuchi session list`.", name)))
+    }
+
+    /// The session created for `root` in project mode, if any.
+    fn for_project(&self, root: &Path) -> Option<String> {
+        self.sessions
+            .iter()
+            .find(|(_, session)| session.project_root.as_deref() == Some(root))
+            .map(|(name, _)| name.clone())
+    }
+
+    /// A free name for a new session of `root`, based on the repository's directory name.
+    fn name_for_project(&self, root: &Path) -> String {
+        let base: String = root
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+            .collect();
+        let base = if base.is_empty() || base == DEFAULT_SESSION { "project".to_string() } else { base };
+        (1..)
+            .map(|n| if n == 1 { base.clone() } else { format!("{}-{}", base, n) })
+            .find(|name| !self.sessions.contains_key(name))
+            .unwrap_or(base)
+    }
+}
+
+/// Points `config` at the channel of this run's session: `--session`/`YUCHI_SESSION`, then the
+/// git repository's session in project mode, then the profile's own channel. A session that doesn't
+/// exist yet is only started by commands that talk to the shape; for the others an unknown
+/// `--session` is an error, and a repository without a session uses the profile's channel.
+pub fn activate(config: &mut Config) -> Result<(), YuchiError> {
+    let requested = SESSION.get();
+    if config.env_only {
+        if requested.is_some() {
+            return Err(YuchiError::Config(
+                "Sessions need a stored config; --session can't be used in environment-only mode.".to_string(),
+            ));
+        }
+        return Ok(());
+    }
+
+    let mut store = Store::load()?;
+    let starts_sessions = STARTS_SESSIONS.load(Ordering::SeqCst);
+    let (name, project_root) = match requested {
+        Some(name) => (name.clone(), None),
+        None => match git_root().filter(|_| config.project_sessions()) {
+            Some(root) => match store.for_project(&root) {
+                Some(name) => (name, None),
+                None if starts_sessions => (store.name_for_project(&root), Some(root)),
+                None => (DEFAULT_SESSION.to_string(), None),
+            },
+            None => (DEFAULT_SESSION.to_string(), None),
+        },
+    };
+
+    if name != DEFAULT_SESSION {
+        if !store.sessions.contains_key(&name) {
+            if !starts_sessions {
+                return Err(YuchiError::Input(format!(
+                    "No session named '{}'. See `yuchi session list`; `yuchi ask` or `yuchi chat` with \
+                     --session {} starts it.",
+                    name, name
+                )));
+            }
+            store.sessions.insert(name.clone(), Session::new(Uuid::new_v4().to_string(), project_root));
+            store.save()?;
+        }
+        config.channel_id = Some(store.get(&name)?.channel_id.clone());
+        config.origins.insert("channel_id", Origin::Session(name.clone()));
+    }
+    log::debug!(session = name.as_str(), channel_id:? = config.channel_id; "active session");
+    config.session = Some(name);
+    Ok(())
+}
+
+/// Appends an exchange to the active session's transcript, dropping the oldest beyond the limit.
+pub fn record(config: &Config, question: &str, reply: &str) -> Result<(), YuchiError> {
+    let (Some(name), Some(channel_id)) = (&config.session, &config.channel_id) else {
+        return Ok(());
+    };
+    let mut store = Store::load()?;
+    let session = store
+        .sessions
+        .entry(name.clone())
+        .or_insert_with(|| Session::new(channel_id.clone(), None));
+    // The default session follows the profile's channel, which `login` may have replaced.
+    session.channel_id = channel_id.clone();
+    session.last_used = now();
+    session.transcript.push(Exchange {
+        question: question.to_string(),
+        reply: reply.to_string(),
+    });
+    let excess = session.transcript.len().saturating_sub(MAX_TRANSCRIPT);
+    session.transcript.drain(..excess);
+    store.save()
+}
+
+/// A recap of `transcript` to open a forked conversation with.
+pub fn summary(transcript: &[Exchange]) -> String {
+    let mut summary =
+        "We're continuing an earlier conversation. Here is a recap of it; reply only with a short acknowledgement.\n"
+            .to_string();
+    for exchange in transcript {
+        summary.push_str(&format!(
+            "\nMe: {}\nYou: {}\n",
+            truncate(&exchange.question, 300),
+            truncate(&exchange.reply, 300)
+        ));
+    }
+    summary
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// The root of the git repository containing the current directory.
+pub fn git_root() -> Option<PathBuf> {
+    let cwd = env::current_dir().ok()?;
+    cwd.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .map(Path::to_path_buf)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
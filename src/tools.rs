@@ -0,0 +1,1248 @@
+use crate::approvals::{self, Decision};
+use crate::backup;
+use crate::config::{CustomTool, ToolPolicy};
+use crate::errors::YuchiError;
+use crate::patch;
+use crate::process::{self, Limits, ToolEnv};
+use crate::redact::Redactor;
+use crate::sandbox::Sandbox;
+use crate::schema;
+use crate::session;
+use colored::Colorize;
+use crate::ui::{
+    confirm, display_command_result, display_diff, display_hunk, display_preview, display_progress, display_table,
+    read_line,
+};
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::WalkBuilder;
+use indicatif::{MultiProgress, ProgressBar};
+use regex::RegexBuilder;
+use serde_json::{json, Map, Value};
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// Most a single `read_file` returns; the shape can ask for a line range to see more.
+const MAX_READ_BYTES: usize = 64 * 1024;
+
+/// Lines of a read shown in its confirmation prompt.
+const PREVIEW_LINES: usize = 10;
+
+/// Most paths `glob_files` returns.
+const MAX_GLOB_RESULTS: usize = 200;
+
+/// Most matching lines `search_text` returns.
+const MAX_SEARCH_MATCHES: usize = 100;
+
+/// Files larger than this are skipped by `search_text`.
+const MAX_SEARCH_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Matching lines are cut to this many characters.
+const MAX_MATCH_CHARS: usize = 200;
+
+/// What tool calls may do in this run; see [`Config::tool_settings`](crate::config::Config::tool_settings).
+#[derive(Clone)]
+pub struct ToolSettings {
+    pub policy: ToolPolicy,
+    /// Run `glob_files` and `search_text` without asking; they can't change anything.
+    pub auto_approve_search: bool,
+    /// Tools declared in config.
+    pub custom: Vec<CustomTool>,
+    /// Where commands run.
+    pub sandbox: Sandbox,
+    /// The environment commands start with.
+    pub env: ToolEnv,
+    pub limits: Limits,
+    /// Masks secrets in results before they go back to the shape.
+    pub redactor: Redactor,
+}
+
+impl ToolSettings {
+    /// Refuses every tool, for requests that must not run anything locally.
+    pub fn deny() -> Self {
+        ToolSettings {
+            policy: ToolPolicy::Deny,
+            auto_approve_search: false,
+            custom: Vec::new(),
+            sandbox: Sandbox::Off,
+            env: ToolEnv::default(),
+            limits: Limits::default(),
+            // Nothing runs, so there are no results to mask.
+            redactor: Redactor::none(),
+        }
+    }
+}
+
+/// Tools offered to the shape when the tool policy allows them: the built-in ones, then those
+/// declared in config.
+pub fn schemas(settings: &ToolSettings) -> Vec<Value> {
+    let mut schemas = builtin_schemas();
+    schemas.extend(settings.custom.iter().map(|tool| {
+        json!({
+            "type": "function",
+            "function": {
+                "name": tool.name,
+                "description": tool.description,
+                "parameters": tool.parameters.clone().unwrap_or_else(|| json!({ "type": "object", "properties": {} }))
+            }
+        })
+    }));
+    schemas
+}
+
+/// Whether `name` belongs to a built-in tool, which config can't redefine.
+pub fn is_builtin(name: &str) -> bool {
+    builtin_schemas().iter().any(|schema| schema["function"]["name"] == name)
+}
+
+fn builtin_schemas() -> Vec<Value> {
+    vec![
+        json!({
+            "type": "function",
+            "function": {
+                "name": "run_shell_command",
+                "description": "Run a shell command in the current directory",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "command": {
+                            "type": "string",
+                            "description": "The shell command to run (e.g., npm install express)"
+                        }
+                    },
+                    "required": ["command"]
+                }
+            }
+        }),
+        json!({
+            "type": "function",
+            "function": {
+                "name": "read_file",
+                "description": "Read a text file in the project, optionally only a range of lines. Lines are numbered in the result.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "File path relative to the project root" },
+                        "start_line": { "type": "integer", "description": "First line to read, starting at 1" },
+                        "end_line": { "type": "integer", "description": "Last line to read, inclusive" }
+                    },
+                    "required": ["path"]
+                }
+            }
+        }),
+        json!({
+            "type": "function",
+            "function": {
+                "name": "write_file",
+                "description": "Create a file in the project or replace its whole content",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "File path relative to the project root" },
+                        "content": { "type": "string", "description": "The complete new content of the file" }
+                    },
+                    "required": ["path", "content"]
+                }
+            }
+        }),
+        json!({
+            "type": "function",
+            "function": {
+                "name": "edit_file",
+                "description": "Replace one exact piece of text in a project file. The text must appear exactly once; include surrounding lines to make it unique.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "File path relative to the project root" },
+                        "old_text": { "type": "string", "description": "The exact text to replace, without line numbers" },
+                        "new_text": { "type": "string", "description": "The replacement text" }
+                    },
+                    "required": ["path", "old_text", "new_text"]
+                }
+            }
+        }),
+        json!({
+            "type": "function",
+            "function": {
+                "name": "apply_patch",
+                "description": "Change one or more project files with a unified diff. Each file needs '--- a/path' and '+++ b/path' headers ('/dev/null' to create or delete) followed by '@@' hunks with a few lines of context. The user reviews each hunk.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "patch": { "type": "string", "description": "The unified diff" }
+                    },
+                    "required": ["patch"]
+                }
+            }
+        }),
+        json!({
+            "type": "function",
+            "function": {
+                "name": "glob_files",
+                "description": "Find project files by glob pattern, e.g. '**/*.rs' or 'src/*.toml'. A pattern without '/' matches file names anywhere. Files ignored by .gitignore and hidden files are skipped.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "pattern": { "type": "string", "description": "Glob pattern" },
+                        "path": { "type": "string", "description": "Directory to search in, relative to the project root (default: the root)" }
+                    },
+                    "required": ["pattern"]
+                }
+            }
+        }),
+        json!({
+            "type": "function",
+            "function": {
+                "name": "search_text",
+                "description": "Search project files for a regular expression and return matching lines as 'path:line: text'. Files ignored by .gitignore, hidden files and binary files are skipped.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "pattern": { "type": "string", "description": "Regular expression (Rust regex syntax)" },
+                        "path": { "type": "string", "description": "File or directory to search, relative to the project root (default: the root)" },
+                        "glob": { "type": "string", "description": "Only search files whose name or path matches this glob, e.g. '*.rs'" },
+                        "case_insensitive": { "type": "boolean", "description": "Ignore case when matching" }
+                    },
+                    "required": ["pattern"]
+                }
+            }
+        }),
+        json!({
+            "type": "function",
+            "function": {
+                "name": "ask_user",
+                "description": "Ask the user a clarifying question and wait for the answer, instead of guessing or ending the turn",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "question": { "type": "string", "description": "The question to ask" },
+                        "choices": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Suggested answers; the user may pick one by number or answer freely"
+                        }
+                    },
+                    "required": ["question"]
+                }
+            }
+        }),
+        json!({
+            "type": "function",
+            "function": {
+                "name": "list_directory",
+                "description": "List the entries of a directory in the project; directories end with '/'",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Directory path relative to the project root (default: the root)" }
+                    }
+                }
+            }
+        }),
+    ]
+}
+
+/// Read-only tools, which a batch runs side by side once approved.
+const READ_ONLY: &[&str] = &["read_file", "list_directory", "glob_files", "search_text"];
+
+/// Whether a tool call still needs the user's go-ahead.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Approval {
+    /// Confirm it, with a preview where there is one.
+    Ask,
+    /// Approved on the batch screen: run without asking and without printing, except that
+    /// file changes still show their diff for confirmation.
+    Approved,
+}
+
+/// One tool call from the shape; `arguments` holds why they couldn't be parsed, if they couldn't.
+pub struct Call {
+    pub name: String,
+    pub arguments: Result<Value, String>,
+}
+
+/// Runs the tool `name` asked for by the shape, after the user approves it. Arguments that don't
+/// fit the tool's schema, and mistakes the shape can correct like a path outside the project,
+/// come back as the result so the shape can try again.
+pub fn run(
+    name: &str,
+    args: &Value,
+    settings: &ToolSettings,
+    approval: Approval,
+    pb: Option<&ProgressBar>,
+) -> Result<String, YuchiError> {
+    if let Err(result) = check(name, args, settings) {
+        display_command_result(name, &result);
+        return Ok(result);
+    }
+    let empty = Map::new();
+    let args = args.as_object().unwrap_or(&empty);
+    match dispatch(name, args, settings, approval, pb) {
+        Err(YuchiError::Tool(message)) => {
+            if approval == Approval::Ask {
+                display_command_result(name, &message);
+            }
+            Ok(message)
+        }
+        result => result,
+    }
+}
+
+/// Runs several tool calls of one reply. After a single approval screen for the tools that don't run
+/// commands, approved read-only calls run concurrently and the rest one after another, each command
+/// with its own prompt; results come back in the order of `calls`.
+pub fn run_batch(calls: &[Call], settings: &ToolSettings, pb: Option<&ProgressBar>) -> Result<Vec<String>, YuchiError> {
+    if let [call] = calls {
+        return Ok(vec![match &call.arguments {
+            Ok(args) => run(&call.name, args, settings, Approval::Ask, pb)?,
+            Err(problem) => {
+                let result = format!("Invalid arguments for {}: {}", call.name, problem);
+                display_command_result(&call.name, &result);
+                result
+            }
+        }]);
+    }
+
+    let mut results: Vec<Option<String>> = calls
+        .iter()
+        .map(|call| match &call.arguments {
+            Err(problem) => Some(format!("Invalid arguments for {}: {}", call.name, problem)),
+            Ok(args) => check(&call.name, args, settings).err(),
+        })
+        .collect();
+    for (call, result) in calls.iter().zip(&results) {
+        if let Some(result) = result {
+            display_command_result(&call.name, result);
+        }
+    }
+
+    // Searches run without asking when `search_approval` allows it, batched or not.
+    let searches: Vec<usize> = (0..calls.len())
+        .filter(|&i| results[i].is_none() && settings.auto_approve_search)
+        .filter(|&i| matches!(calls[i].name.as_str(), "glob_files" | "search_text"))
+        .collect();
+
+    // `ask_user` only asks a question, and with tools denied there's nothing to approve. Commands
+    // are asked about one at a time, with every choice a single command gets.
+    let commands = (0..calls.len()).any(|i| results[i].is_none() && runs_command(settings, &calls[i].name));
+    let pending: Vec<usize> = (0..calls.len())
+        .filter(|&i| results[i].is_none() && calls[i].name != "ask_user")
+        .filter(|&i| !runs_command(settings, &calls[i].name) && !searches.contains(&i))
+        .collect();
+    let mut approved = if settings.policy == ToolPolicy::Deny || pending.is_empty() {
+        Vec::new()
+    } else {
+        let rows: Vec<Vec<String>> = pending
+            .iter()
+            .map(|&i| vec![(i + 1).to_string(), calls[i].name.clone(), describe(&calls[i], settings)])
+            .collect();
+        println!("{}", format!("The shape wants to run {} tools:", pending.len()).bold());
+        display_table(&["#", "Tool", "Details"], &rows);
+        if commands {
+            println!("The commands it also asked for are confirmed one at a time afterwards.");
+        }
+        let approved = choose_calls(&pending)?;
+        for &i in pending.iter().filter(|i| !approved.contains(i)) {
+            results[i] = Some(format!("{} was rejected by the user.", calls[i].name));
+        }
+        approved
+    };
+    approved.extend(searches);
+
+    let (parallel, sequential): (Vec<usize>, Vec<usize>) = (0..calls.len())
+        .filter(|&i| results[i].is_none())
+        .partition(|&i| approved.contains(&i) && READ_ONLY.contains(&calls[i].name.as_str()));
+
+    for (i, result) in run_concurrently(calls, &parallel, settings)? {
+        results[i] = Some(result);
+    }
+    for i in sequential {
+        let approval = if approved.contains(&i) { Approval::Approved } else { Approval::Ask };
+        let args = calls[i].arguments.as_ref().unwrap_or(&Value::Null);
+        results[i] = Some(run(&calls[i].name, args, settings, approval, pb)?);
+    }
+    Ok(results.into_iter().map(Option::unwrap_or_default).collect())
+}
+
+/// Asks which of the calls `pending` (indexes into the batch) may run.
+fn choose_calls(pending: &[usize]) -> Result<Vec<usize>, YuchiError> {
+    loop {
+        match read_line("Run them? [a]ll/[s]elect/[r]eject all: ")?.to_lowercase().as_str() {
+            "a" | "all" => return Ok(pending.to_vec()),
+            "r" | "reject" => return Ok(Vec::new()),
+            "s" | "select" => {}
+            _ => continue,
+        }
+
+        let input = read_line("Numbers to run, e.g. 1,3: ")?;
+        let picked: Option<Vec<usize>> = input
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|part| !part.is_empty())
+            .map(|part| part.parse::<usize>().ok().map(|n| n.wrapping_sub(1)).filter(|i| pending.contains(i)))
+            .collect();
+        match picked {
+            Some(picked) => return Ok(picked),
+            None => println!("{}", "Pick numbers from the list.".yellow()),
+        }
+    }
+}
+
+/// Runs the approved read-only calls at `indexes` side by side, with a live line for each.
+fn run_concurrently(
+    calls: &[Call],
+    indexes: &[usize],
+    settings: &ToolSettings,
+) -> Result<Vec<(usize, String)>, YuchiError> {
+    if indexes.is_empty() {
+        return Ok(Vec::new());
+    }
+    let lines = MultiProgress::new();
+    thread::scope(|scope| {
+        let handles: Vec<_> = indexes
+            .iter()
+            .map(|&i| {
+                let call = &calls[i];
+                let line = lines.add(display_progress());
+                line.set_message(format!("{} {}", call.name, describe(call, settings)));
+                scope.spawn(move || {
+                    let args = call.arguments.as_ref().unwrap_or(&Value::Null);
+                    let result = run(&call.name, args, settings, Approval::Approved, None);
+                    let status = match &result {
+                        Ok(text) => {
+                            let size = format!("({} lines)", text.lines().count()).dimmed();
+                            format!("{} {} {} {}", "✔".green(), call.name, describe(call, settings), size)
+                        }
+                        Err(e) => format!("{} {} {}: {}", "✘".red(), call.name, describe(call, settings), e),
+                    };
+                    line.finish_with_message(status);
+                    result.map(|text| (i, text))
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err(YuchiError::Tool("A tool panicked.".to_string()))))
+            .collect()
+    })
+}
+
+/// Label, words, and how to split an edit of them, for calls of the tools that run a command.
+type CommandLine = (String, Vec<String>, fn(&str) -> Option<Vec<String>>);
+
+/// The command a call would run, if its tool runs one.
+fn command_line(
+    name: &str,
+    args: &Map<String, Value>,
+    settings: &ToolSettings,
+) -> Result<Option<CommandLine>, YuchiError> {
+    if name == "run_shell_command" {
+        let command = str_arg(args, "command")?;
+        let words = split_words(command).unwrap_or_default();
+        return Ok(Some((command.to_string(), words, split_words)));
+    }
+    let Some(tool) = settings.custom.iter().find(|tool| tool.name == name) else { return Ok(None) };
+    let words = fill_template(&tool.command, args)?;
+    let label = approvals::label(&words);
+    log::debug!(tool = name, command = label.as_str(); "custom tool");
+    Ok(Some((label, words, shlex::split)))
+}
+
+/// `run_shell_command` splits on whitespace only; there is no shell to interpret quotes.
+fn split_words(command: &str) -> Option<Vec<String>> {
+    Some(command.split_whitespace().map(str::to_string).collect())
+}
+
+/// Whether the tool runs a command rather than working on files itself.
+fn runs_command(settings: &ToolSettings, name: &str) -> bool {
+    name == "run_shell_command" || settings.custom.iter().any(|tool| tool.name == name)
+}
+
+/// What a call does, for the approval screen and status lines: the whole command line for tools
+/// that run one, and the gist of the arguments for the rest.
+fn describe(call: &Call, settings: &ToolSettings) -> String {
+    let Ok(args) = &call.arguments else { return String::new() };
+    if let Some(Ok(Some((label, _, _)))) = args.as_object().map(|args| command_line(&call.name, args, settings)) {
+        return label;
+    }
+    let text = ["command", "path", "pattern", "question"]
+        .iter()
+        .find_map(|key| args.get(key).and_then(Value::as_str))
+        .map(str::to_string)
+        .unwrap_or_else(|| args.to_string());
+    match text.char_indices().nth(80) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }
+}
+
+/// The result to send instead of running a call whose tool is unknown or whose arguments don't fit its schema.
+fn check(name: &str, args: &Value, settings: &ToolSettings) -> Result<(), String> {
+    let Some(schema) = schemas(settings).into_iter().find(|schema| schema["function"]["name"] == name) else {
+        return Err(format!("Unknown tool '{}'.", name));
+    };
+    let problems = schema::validate(&schema["function"]["parameters"], args);
+    if !problems.is_empty() {
+        log::debug!(tool = name, problems:? = problems; "invalid tool arguments");
+        return Err(format!("Invalid arguments for {}: {}. Fix them and call the tool again.", name, problems.join("; ")));
+    }
+    Ok(())
+}
+
+fn dispatch(
+    name: &str,
+    args: &Map<String, Value>,
+    settings: &ToolSettings,
+    approval: Approval,
+    pb: Option<&ProgressBar>,
+) -> Result<String, YuchiError> {
+    if let Some((command, words, parse)) = command_line(name, args, settings)? {
+        return run_command(&command, words, parse, settings, approval, pb);
+    }
+    if settings.policy == ToolPolicy::Deny {
+        let result = "Tool use is disabled by the tool policy.".to_string();
+        display_command_result(name, &result);
+        return Ok(result);
+    }
+
+    log::info!(tool = name; "running tool");
+    match name {
+        "read_file" => read_file(args, approval),
+        "write_file" => write_file(args),
+        "edit_file" => edit_file(args),
+        "list_directory" => list_directory(args, approval),
+        "apply_patch" => apply_patch(args),
+        "glob_files" => glob_files(args, settings, approval),
+        "search_text" => search_text(args, settings, approval),
+        "ask_user" => ask_user(args),
+        _ => Err(YuchiError::Tool(format!("Unknown tool '{}'.", name))),
+    }
+}
+
+/// Splits a custom tool's command and fills in `{name}` placeholders with the arguments. Values go
+/// into single words, so they can't reach a shell. Before a literal `--`, a filled-in word may only
+/// start with `-` if the template's does, so values can't pass as options. A word that was only a
+/// placeholder for a missing argument is left out.
+fn fill_template(template: &str, args: &Map<String, Value>) -> Result<Vec<String>, YuchiError> {
+    let words = shlex::split(template)
+        .ok_or_else(|| YuchiError::Config(format!("Can't parse the tool command `{}`", template)))?;
+    let mut filled = Vec::new();
+    let mut options_ended = false;
+    for word in words {
+        let names = placeholders(&word);
+        if names.len() == 1 && word == format!("{{{}}}", names[0]) && args.get(&names[0]).is_none_or(Value::is_null) {
+            continue;
+        }
+        let result = fill_word(&word, args);
+        if !options_ended && result.starts_with('-') && !word.starts_with('-') {
+            return Err(YuchiError::Tool(format!(
+                "`{}` can't start with `-`, since the command would read it as an option. \
+                 For a file, use a path like `./{}`.",
+                names.join("`/`"),
+                result
+            )));
+        }
+        options_ended |= word == "--";
+        filled.push(result);
+    }
+    if filled.is_empty() {
+        return Err(YuchiError::Config(format!("The tool command `{}` is empty", template)));
+    }
+    Ok(filled)
+}
+
+/// The `{name}` placeholders in one word of a command template.
+pub fn placeholders(word: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = word;
+    while let Some(start) = rest.find('{') {
+        let after = &rest[start + 1..];
+        match after.find('}') {
+            Some(end) if is_placeholder(&after[..end]) => {
+                names.push(after[..end].to_string());
+                rest = &after[end + 1..];
+            }
+            _ => rest = after,
+        }
+    }
+    names
+}
+
+/// Replaces the placeholders in one word in a single pass, so a value that contains `{other}` is
+/// kept as it is rather than filled in again.
+fn fill_word(word: &str, args: &Map<String, Value>) -> String {
+    let mut filled = String::new();
+    let mut rest = word;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('}') {
+            Some(end) if is_placeholder(&after[..end]) => {
+                match args.get(&after[..end]) {
+                    None | Some(Value::Null) => {}
+                    Some(Value::String(text)) => filled.push_str(text),
+                    Some(other) => filled.push_str(&other.to_string()),
+                }
+                rest = &after[end + 1..];
+            }
+            _ => {
+                filled.push('{');
+                rest = after;
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+fn is_placeholder(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Runs `words` as a program and its arguments once the user approves; `command` is how it's shown
+/// and `parse` splits it again if the user edits it.
+fn run_command(
+    command: &str,
+    words: Vec<String>,
+    parse: fn(&str) -> Option<Vec<String>>,
+    settings: &ToolSettings,
+    approval: Approval,
+    pb: Option<&ProgressBar>,
+) -> Result<String, YuchiError> {
+    if settings.policy == ToolPolicy::Deny {
+        let result = "Command execution is disabled by the tool policy.".to_string();
+        display_command_result(command, &result);
+        return Ok(result);
+    }
+
+    // Checked before asking, so an empty prefix can never be allowed.
+    if words.is_empty() {
+        return Err(YuchiError::Tool("Empty command".to_string()));
+    }
+    let root = project_root()?;
+    let mut command = command.to_string();
+    let mut words = words;
+    // Tells the shape when the user ran something other than what it asked for.
+    let mut note = String::new();
+    if approval == Approval::Ask {
+        if approvals::allowed(&root, &words) {
+            println!("{}", format!("Running `{}`, which is always allowed in this project.", command).dimmed());
+        } else {
+            match approvals::ask(words.clone(), &root, settings, parse)? {
+                Decision::Deny(reason) => {
+                    let mut result = format!("The user declined to run `{}`.", command);
+                    if let Some(reason) = reason {
+                        result.push_str(&format!(" Their reason: {}", reason));
+                    }
+                    display_command_result(&command, &result);
+                    return Ok(result);
+                }
+                Decision::Run(approved) if approved != words => {
+                    let edited = approvals::label(&approved);
+                    note = format!("The user changed the command from `{}` to `{}`.\n", command, edited);
+                    command = edited;
+                    words = approved;
+                }
+                Decision::Run(_) => {}
+            }
+        }
+    }
+    let command = command.as_str();
+
+    let pb = pb.cloned().unwrap_or_else(display_progress);
+    log::info!(command; "running tool");
+
+    let Some((program, args)) = words.split_first() else {
+        return Err(YuchiError::Tool("Empty command".to_string()));
+    };
+
+    let sandboxed = settings.sandbox.command(program, args, &root)?;
+    let output = process::output(sandboxed, &settings.env, &settings.limits)
+        .map_err(|e| settings.sandbox.spawn_error(command, e))?;
+    if process::take_interrupted() {
+        pb.finish_and_clear();
+        return Err(YuchiError::Cancelled(format!("`{}` was interrupted.", command)));
+    }
+
+    log::debug!(command, status:% = output.status, stdout_bytes = output.stdout.len(); "tool finished");
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+
+    let result = if output.status.success() {
+        format!("{}`{}` succeeded:\n{}", note, command, stdout)
+    } else if let Some(reason) = process::describe_failure(&output.status) {
+        format!("{}`{}` failed because {}:\n{}", note, command, reason, stderr)
+    } else {
+        format!("{}`{}` failed:\n{}", note, command, stderr)
+    };
+
+    display_command_result(command, &result);
+    pb.finish_and_clear();
+
+    Ok(result)
+}
+
+fn read_file(args: &Map<String, Value>, approval: Approval) -> Result<String, YuchiError> {
+    let (root, path) = project_path(args, "path")?;
+    let display = relative(&root, &path);
+    let text = read_text(&path, &display)?;
+
+    let lines: Vec<&str> = text.lines().collect();
+    let start = line_arg(args, "start_line")?.unwrap_or(1).max(1);
+    let end = line_arg(args, "end_line")?.unwrap_or(lines.len()).min(lines.len());
+    if start > end && !lines.is_empty() {
+        return Err(YuchiError::Tool(format!(
+            "Line range {}-{} is empty; '{}' has {} lines.",
+            start,
+            end,
+            display,
+            lines.len()
+        )));
+    }
+
+    let mut numbered = String::new();
+    let mut truncated = false;
+    for (index, line) in lines.iter().enumerate().take(end).skip(start - 1) {
+        let row = format!("{:>6}\t{}\n", index + 1, line);
+        if numbered.len() + row.len() > MAX_READ_BYTES {
+            truncated = true;
+            break;
+        }
+        numbered.push_str(&row);
+    }
+
+    let range = if lines.is_empty() { "empty file".to_string() } else { format!("lines {}-{} of {}", start, end, lines.len()) };
+    if approval == Approval::Ask {
+        println!("Read {} ({})?", display.bold(), range);
+        display_preview(&numbered, PREVIEW_LINES);
+        if !confirm("Send this to the shape?")? {
+            return Ok(cancelled("read_file", &display));
+        }
+    }
+
+    if truncated {
+        numbered.push_str("[truncated; read a smaller line range to see more]\n");
+    }
+    if approval == Approval::Ask {
+        display_command_result(&format!("read_file {}", display), &format!("Sent {}", range));
+    }
+    Ok(numbered)
+}
+
+fn write_file(args: &Map<String, Value>) -> Result<String, YuchiError> {
+    let (root, path) = project_path(args, "path")?;
+    let display = relative(&root, &path);
+    let content = str_arg(args, "content")?;
+    if path.is_dir() {
+        return Err(YuchiError::Tool(format!("'{}' is a directory.", display)));
+    }
+    let old = if path.exists() { read_text(&path, &display)? } else { String::new() };
+
+    let action = if path.exists() { "Overwrite" } else { "Create" };
+    display_diff(&display, &old, content);
+    if !confirm(&format!("{} {}?", action, display))? {
+        return Ok(cancelled("write_file", &display));
+    }
+
+    backup::write_files("write_file", &root, &[(path, Some(content.to_string()))])?;
+    let result = format!("Wrote {} bytes to {}.", content.len(), display);
+    display_command_result(&format!("write_file {}", display), &result);
+    Ok(result)
+}
+
+fn edit_file(args: &Map<String, Value>) -> Result<String, YuchiError> {
+    let (root, path) = project_path(args, "path")?;
+    let display = relative(&root, &path);
+    let old_text = str_arg(args, "old_text")?;
+    let new_text = str_arg(args, "new_text")?;
+    if old_text.is_empty() {
+        return Err(YuchiError::Tool("old_text must not be empty; use write_file to create files.".to_string()));
+    }
+    let old = read_text(&path, &display)?;
+
+    match old.matches(old_text).count() {
+        0 => {
+            return Err(YuchiError::Tool(format!(
+                "old_text was not found in '{}'. Read the file again and copy the text exactly.",
+                display
+            )))
+        }
+        1 => {}
+        count => {
+            return Err(YuchiError::Tool(format!(
+                "old_text appears {} times in '{}'; include more surrounding lines so it matches once.",
+                count, display
+            )))
+        }
+    }
+    let new = old.replacen(old_text, new_text, 1);
+
+    display_diff(&display, &old, &new);
+    if !confirm(&format!("Apply this edit to {}?", display))? {
+        return Ok(cancelled("edit_file", &display));
+    }
+
+    backup::write_files("edit_file", &root, &[(path, Some(new))])?;
+    let result = format!("Edited {}.", display);
+    display_command_result(&format!("edit_file {}", display), &result);
+    Ok(result)
+}
+
+/// A user's answer when reviewing a hunk.
+#[derive(Clone, Copy, PartialEq)]
+enum Review {
+    Yes,
+    No,
+    All,
+    Quit,
+}
+
+fn review(question: &str) -> Result<Review, YuchiError> {
+    loop {
+        match read_line(&format!("{} [y]es/[n]o/[a]ll/[q]uit: ", question))?.to_lowercase().as_str() {
+            "y" | "yes" => return Ok(Review::Yes),
+            "n" | "no" => return Ok(Review::No),
+            "a" | "all" => return Ok(Review::All),
+            "q" | "quit" => return Ok(Review::Quit),
+            _ => continue,
+        }
+    }
+}
+
+/// Reviews a multi-file patch hunk by hunk, then writes the accepted hunks all at once.
+/// Nothing is written unless every file of the patch applies cleanly.
+fn apply_patch(args: &Map<String, Value>) -> Result<String, YuchiError> {
+    let root = project_root()?;
+    let files = patch::parse(str_arg(args, "patch")?)?;
+    if files.is_empty() {
+        return Err(YuchiError::Tool(
+            "No file changes found; the patch needs '--- a/path' and '+++ b/path' headers.".to_string(),
+        ));
+    }
+
+    // Check the whole patch before asking about any of it.
+    let mut targets = Vec::new();
+    for file in &files {
+        let old = file.old_path.as_deref().map(|p| confine(&root, p)).transpose()?;
+        let new = file.new_path.as_deref().map(|p| confine(&root, p)).transpose()?;
+        let original = match &old {
+            Some(path) => read_text(path, file.old_path.as_deref().unwrap_or_default())?,
+            None if new.as_ref().is_some_and(|p| p.exists()) => {
+                return Err(YuchiError::Tool(format!("'{}' already exists.", file.path())))
+            }
+            None => String::new(),
+        };
+        // Like git, a rename doesn't overwrite a file that's already there.
+        if let (Some(from), Some(to)) = (&old, &new) {
+            if from != to && to.exists() {
+                return Err(YuchiError::Tool(format!(
+                    "Can't rename '{}' to '{}': '{}' already exists.",
+                    file.old_path.as_deref().unwrap_or_default(),
+                    file.path(),
+                    file.path()
+                )));
+            }
+        }
+        let hunks: Vec<&patch::Hunk> = file.hunks.iter().collect();
+        patch::apply(&original, &hunks).map_err(|e| YuchiError::Tool(format!("In '{}': {}.", file.path(), e)))?;
+        targets.push((old, new, original));
+    }
+
+    let mut changes: Vec<(PathBuf, Option<String>)> = Vec::new();
+    let mut summary = Vec::new();
+    let mut answer = Review::Yes;
+    for (file, (old, new, original)) in files.iter().zip(targets) {
+        let title = match (&file.old_path, &file.new_path) {
+            (None, _) => format!("{} (new file)", file.path()),
+            (_, None) => format!("{} (deleted)", file.path()),
+            (Some(from), Some(to)) if from != to => format!("{} (renamed from {})", to, from),
+            _ => file.path().to_string(),
+        };
+        println!("{}", title.bold());
+
+        let mut accepted = Vec::new();
+        for (index, hunk) in file.hunks.iter().enumerate() {
+            if answer == Review::Quit {
+                break;
+            }
+            display_hunk(hunk);
+            if !matches!(answer, Review::All | Review::Quit) {
+                answer = review(&format!("Apply hunk {}/{} to {}?", index + 1, file.hunks.len(), file.path()))?;
+            }
+            if matches!(answer, Review::Yes | Review::All) {
+                accepted.push(hunk);
+            }
+        }
+        summary.push(format!("{} ({}/{} hunks)", file.path(), accepted.len(), file.hunks.len()));
+        if accepted.is_empty() {
+            continue;
+        }
+
+        // A deletion or rename only makes sense with all of the file's hunks.
+        let whole = accepted.len() == file.hunks.len();
+        let content = patch::apply(&original, &accepted).map_err(|e| YuchiError::Tool(format!("In '{}': {}.", file.path(), e)))?;
+        match (old, new) {
+            (Some(old), None) if whole => changes.push((old, None)),
+            (Some(old), Some(new)) if whole && old != new => {
+                changes.push((old, None));
+                changes.push((new, Some(content)));
+            }
+            (Some(old), _) => changes.push((old, Some(content))),
+            (None, Some(new)) => changes.push((new, Some(content))),
+            (None, None) => {}
+        }
+    }
+
+    if changes.is_empty() {
+        return Ok(cancelled("apply_patch", &summary.join(", ")));
+    }
+    backup::write_files("apply_patch", &root, &changes)?;
+    let result = format!(
+        "Applied the patch: {}. Hunks that were not accepted were left out; `yuchi undo` reverts it.",
+        summary.join(", ")
+    );
+    display_command_result("apply_patch", &result);
+    Ok(result)
+}
+
+fn list_directory(args: &Map<String, Value>, approval: Approval) -> Result<String, YuchiError> {
+    let root = project_root()?;
+    let path = optional_path(&root, args)?;
+    let display = relative(&root, &path);
+    let entries = fs::read_dir(&path)
+        .map_err(|e| YuchiError::Tool(format!("Failed to list '{}': {}", display, e)))?;
+
+    let mut names: Vec<String> = entries
+        .filter_map(Result::ok)
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type().is_ok_and(|t| t.is_dir()) { format!("{}/", name) } else { name }
+        })
+        .collect();
+    names.sort();
+    let listing = names.join("\n");
+
+    if approval == Approval::Ask {
+        println!("List {} ({} entries)?", display.bold(), names.len());
+        display_preview(&listing, PREVIEW_LINES);
+        if !confirm("Send this to the shape?")? {
+            return Ok(cancelled("list_directory", &display));
+        }
+        display_command_result(&format!("list_directory {}", display), &format!("Sent {} entries", names.len()));
+    }
+    Ok(listing)
+}
+
+fn glob_files(args: &Map<String, Value>, settings: &ToolSettings, approval: Approval) -> Result<String, YuchiError> {
+    let root = project_root()?;
+    let pattern = str_arg(args, "pattern")?;
+    let base = optional_path(&root, args)?;
+    let matcher = glob_matcher(pattern)?;
+    let ask = approval == Approval::Ask && !settings.auto_approve_search;
+    if ask && !confirm(&format!("Find files matching `{}` in {}?", pattern, relative(&root, &base)))? {
+        return Ok(cancelled("glob_files", pattern));
+    }
+
+    let mut paths: Vec<String> = walk(&base)
+        .filter(|path| matcher.matches(&root, &base, path))
+        .map(|path| relative(&root, &path))
+        .collect();
+    paths.sort();
+    let total = paths.len();
+    paths.truncate(MAX_GLOB_RESULTS);
+
+    let mut result = if paths.is_empty() { format!("No files match `{}`.", pattern) } else { paths.join("\n") };
+    if total > MAX_GLOB_RESULTS {
+        result.push_str(&format!("\n[{} more not shown; use a narrower pattern]", total - MAX_GLOB_RESULTS));
+    }
+    if approval == Approval::Ask {
+        display_command_result(&format!("glob_files {}", pattern), &format!("{} files", total));
+    }
+    Ok(result)
+}
+
+fn search_text(args: &Map<String, Value>, settings: &ToolSettings, approval: Approval) -> Result<String, YuchiError> {
+    let root = project_root()?;
+    let pattern = str_arg(args, "pattern")?;
+    let base = optional_path(&root, args)?;
+    let regex = RegexBuilder::new(pattern)
+        .case_insensitive(args.get("case_insensitive").and_then(Value::as_bool).unwrap_or(false))
+        .build()
+        .map_err(|e| YuchiError::Tool(format!("Invalid regular expression `{}`: {}", pattern, e)))?;
+    let filter = args.get("glob").and_then(Value::as_str).map(glob_matcher).transpose()?;
+    let ask = approval == Approval::Ask && !settings.auto_approve_search;
+    if ask && !confirm(&format!("Search for `{}` in {}?", pattern, relative(&root, &base)))? {
+        return Ok(cancelled("search_text", pattern));
+    }
+
+    let mut matches = Vec::new();
+    let mut more = false;
+    'files: for path in walk(&base) {
+        if filter.as_ref().is_some_and(|filter| !filter.matches(&root, &base, &path)) {
+            continue;
+        }
+        if fs::metadata(&path).map_or(true, |m| m.len() > MAX_SEARCH_FILE_BYTES) {
+            continue;
+        }
+        // Binary and non-UTF-8 files are skipped.
+        let Ok(text) = fs::read_to_string(&path) else { continue };
+        let display = relative(&root, &path);
+        for (index, line) in text.lines().enumerate() {
+            if !regex.is_match(line) {
+                continue;
+            }
+            if matches.len() == MAX_SEARCH_MATCHES {
+                more = true;
+                break 'files;
+            }
+            let line: String = line.trim().chars().take(MAX_MATCH_CHARS).collect();
+            matches.push(format!("{}:{}: {}", display, index + 1, line));
+        }
+    }
+
+    let mut result = if matches.is_empty() { format!("No matches for `{}`.", pattern) } else { matches.join("\n") };
+    if more {
+        result.push_str(&format!("\n[stopped after {} matches; narrow the pattern, path or glob]", MAX_SEARCH_MATCHES));
+    }
+    if approval == Approval::Ask {
+        display_command_result(&format!("search_text {}", pattern), &format!("{} matches", matches.len()));
+    }
+    Ok(result)
+}
+
+/// Puts the shape's question to the user; the answer, or a picked choice, is the result.
+fn ask_user(args: &Map<String, Value>) -> Result<String, YuchiError> {
+    let question = str_arg(args, "question")?;
+    let choices: Vec<&str> = args
+        .get("choices")
+        .and_then(Value::as_array)
+        .map(|choices| choices.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    if !io::stdin().is_terminal() {
+        return Ok("The user can't be asked right now; continue with your best judgement and say what you assumed."
+            .to_string());
+    }
+
+    println!("{} {}", "The shape asks:".bold(), question);
+    for (index, choice) in choices.iter().enumerate() {
+        println!("  {}. {}", index + 1, choice);
+    }
+    let prompt = if choices.is_empty() { "Answer: " } else { "Answer (number or text): " };
+    let input = read_line(prompt)?;
+    let answer = match input.parse::<usize>() {
+        Ok(number) if (1..=choices.len()).contains(&number) => choices[number - 1].to_string(),
+        _ => input,
+    };
+    log::debug!(question, answered = !answer.is_empty(); "asked the user");
+    if answer.is_empty() {
+        return Ok("The user gave no answer.".to_string());
+    }
+    Ok(format!("The user answered: {}", answer))
+}
+
+/// Files under `base` (or `base` itself), skipping hidden files and whatever `.gitignore` excludes.
+fn walk(base: &Path) -> impl Iterator<Item = PathBuf> {
+    WalkBuilder::new(base)
+        .require_git(false)
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+        .map(|entry| entry.into_path())
+}
+
+/// A glob for file names when it has no `/`, otherwise for paths relative to the search directory.
+struct PathGlob {
+    matcher: GlobMatcher,
+    by_name: bool,
+}
+
+impl PathGlob {
+    fn matches(&self, root: &Path, base: &Path, path: &Path) -> bool {
+        if self.by_name {
+            return path.file_name().is_some_and(|name| self.matcher.is_match(name));
+        }
+        let from_base = path.strip_prefix(base).unwrap_or(path);
+        let from_root = path.strip_prefix(root).unwrap_or(path);
+        self.matcher.is_match(from_base) || self.matcher.is_match(from_root)
+    }
+}
+
+fn glob_matcher(pattern: &str) -> Result<PathGlob, YuchiError> {
+    let glob = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| YuchiError::Tool(format!("Invalid glob `{}`: {}", pattern, e)))?;
+    Ok(PathGlob { matcher: glob.compile_matcher(), by_name: !pattern.contains('/') })
+}
+
+/// The optional `path` argument confined to the project, or the project root.
+fn optional_path(root: &Path, args: &Map<String, Value>) -> Result<PathBuf, YuchiError> {
+    match args.get("path").and_then(Value::as_str) {
+        Some(path) => confine(root, path),
+        None => Ok(root.to_path_buf()),
+    }
+}
+
+fn cancelled(tool: &str, display: &str) -> String {
+    let result = format!("{} on {} cancelled by user.", tool, display);
+    display_command_result(tool, &result);
+    result
+}
+
+fn str_arg<'a>(args: &'a Map<String, Value>, key: &str) -> Result<&'a str, YuchiError> {
+    args.get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| YuchiError::Tool(format!("Missing {} parameter", key)))
+}
+
+fn line_arg(args: &Map<String, Value>, key: &str) -> Result<Option<usize>, YuchiError> {
+    match args.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(|n| Some(n as usize))
+            .ok_or_else(|| YuchiError::Tool(format!("{} must be a positive line number.", key))),
+    }
+}
+
+fn read_text(path: &Path, display: &str) -> Result<String, YuchiError> {
+    let bytes = fs::read(path).map_err(|e| YuchiError::Tool(format!("Failed to read '{}': {}", display, e)))?;
+    String::from_utf8(bytes).map_err(|_| YuchiError::Tool(format!("'{}' is not a UTF-8 text file.", display)))
+}
+
+/// The root file tools are confined to: the git repository, or else the current directory.
+pub fn project_root() -> Result<PathBuf, YuchiError> {
+    let root = match session::git_root() {
+        Some(root) => root,
+        None => env::current_dir().map_err(|e| YuchiError::io("Failed to read the current directory", e))?,
+    };
+    root.canonicalize()
+        .map_err(|e| YuchiError::io(format!("Failed to read '{}'", root.display()), e))
+}
+
+fn project_path(args: &Map<String, Value>, key: &str) -> Result<(PathBuf, PathBuf), YuchiError> {
+    let root = project_root()?;
+    let path = confine(&root, str_arg(args, key)?)?;
+    Ok((root, path))
+}
+
+/// Resolves `path` against `root`, following symlinks of the parts that exist, and refuses
+/// anything that ends up outside of it.
+pub fn confine(root: &Path, path: &str) -> Result<PathBuf, YuchiError> {
+    let joined = root.join(path);
+    let mut existing = joined.as_path();
+    let mut missing = Vec::new();
+    while !existing.exists() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name.to_os_string());
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+    let mut resolved = existing
+        .canonicalize()
+        .map_err(|e| YuchiError::Tool(format!("Failed to resolve '{}': {}", path, e)))?;
+    // `file_name` is never `..`, so the parts that don't exist yet can't climb out again.
+    for name in missing.iter().rev() {
+        resolved.push(name);
+    }
+    if !resolved.starts_with(root) {
+        return Err(YuchiError::Tool(format!(
+            "'{}' is outside the project root '{}'.",
+            path,
+            root.display()
+        )));
+    }
+    Ok(resolved)
+}
+
+/// `path` relative to `root` for prompts and results.
+fn relative(root: &Path, path: &Path) -> String {
+    match path.strip_prefix(root) {
+        Ok(rel) if rel.as_os_str().is_empty() => ".".to_string(),
+        Ok(rel) => rel.display().to_string(),
+        Err(_) => path.display().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_without_slash_matches_file_names_anywhere() {
+        let glob = glob_matcher("*.rs").unwrap();
+        let root = Path::new("/project");
+        assert!(glob.matches(root, root, Path::new("/project/src/deep/main.rs")));
+        assert!(!glob.matches(root, root, Path::new("/project/src/main.rsx")));
+    }
+
+    #[test]
+    fn glob_with_slash_matches_paths_from_base_or_root() {
+        let glob = glob_matcher("src/*.rs").unwrap();
+        let root = Path::new("/project");
+        assert!(glob.matches(root, root, Path::new("/project/src/main.rs")));
+        assert!(glob.matches(root, Path::new("/project/src"), Path::new("/project/src/main.rs")));
+        assert!(glob.matches(root, Path::new("/project/crates"), Path::new("/project/crates/src/lib.rs")));
+        assert!(!glob.matches(root, root, Path::new("/project/src/bin/main.rs")));
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        let glob = glob_matcher("src/**/*.rs").unwrap();
+        let root = Path::new("/project");
+        assert!(glob.matches(root, root, Path::new("/project/src/bin/tool/main.rs")));
+        assert!(!glob.matches(root, root, Path::new("/project/tests/main.rs")));
+    }
+
+    #[test]
+    fn invalid_glob_is_a_tool_error() {
+        assert!(matches!(glob_matcher("src/[a"), Err(YuchiError::Tool(_))));
+    }
+
+    fn args(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => Map::new(),
+        }
+    }
+
+    #[test]
+    fn finds_placeholders_in_a_word() {
+        assert_eq!(placeholders("--out={dir}/{name}.txt"), vec!["dir", "name"]);
+        assert_eq!(placeholders("{}{not valid}{{ok}"), vec!["ok"]);
+        assert!(placeholders("plain").is_empty());
+    }
+
+    #[test]
+    fn fills_each_value_into_one_word() {
+        let filled = fill_template("grep -n {pattern} {file}", &args(json!({"pattern": "a b; rm", "file": "x"})));
+        assert_eq!(filled.unwrap(), vec!["grep", "-n", "a b; rm", "x"]);
+    }
+
+    #[test]
+    fn values_are_not_filled_in_again() {
+        let filled = fill_template("echo {a}:{b}", &args(json!({"a": "{b}", "b": "2"})));
+        assert_eq!(filled.unwrap(), vec!["echo", "{b}:2"]);
+    }
+
+    #[test]
+    fn missing_arguments_drop_lone_placeholders() {
+        let filled = fill_template("ls {flags} dir/{name} {path}", &args(json!({"path": 3})));
+        assert_eq!(filled.unwrap(), vec!["ls", "dir/", "3"]);
+    }
+
+    #[test]
+    fn values_cannot_pass_as_options() {
+        let template = "rg {pattern} -- {path}";
+        let filled = fill_template(template, &args(json!({"pattern": "-e", "path": "x"})));
+        assert!(matches!(filled, Err(YuchiError::Tool(_))));
+        let filled = fill_template(template, &args(json!({"pattern": "x", "path": "-rf"})));
+        assert_eq!(filled.unwrap(), vec!["rg", "x", "--", "-rf"]);
+        let filled = fill_template("sort -{flags}", &args(json!({"flags": "-r"})));
+        assert_eq!(filled.unwrap(), vec!["sort", "--r"]);
+    }
+}
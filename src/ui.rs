@@ -1,40 +1,50 @@
 use colored::Colorize;
 use indicatif::{ProgressBar, ProgressStyle};
 use prettytable::{Table, Row, Cell};
+use crate::api::Latency;
 use crate::errors::YuchiError;
+use crate::patch::{Hunk, Line};
+use similar::TextDiff;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::Duration;
 
-pub fn display_help() {
-    println!("{}", "=== Yuchi CLI v0.2.0 ===".bold().cyan());
-    println!("A command-line assistant powered by ShapesAI.");
-    println!("\nUsage: yuchi [OPTIONS] [QUESTION...]");
-    println!("\nOptions:");
-    println!("  --login                  Authenticate with ShapesAI (API key or user auth token)");
-    println!("  --shape <USERNAME>       Set a ShapesAI username to use a custom model (shapesinc/<username>)");
-    println!("  --logout                 Clear stored credentials and configuration");
-    println!("  --reset                  Reset the AI conversation history (sends '!reset' to AI)");
-    println!("  --wack                   Clear the AI's short-term memory (sends '!wack' to AI)");
-    println!("  --sleep                  Save the current conversation state");
-    println!("  --model <MODEL>          Override the model for this question");
-    println!("  --image <IMAGE_PATH>     Path to an image file (PNG/JPEG) to send to the AI");
-    println!("  --imagine                Generate an image via AI and download it (appends '!imagine' to the prompt)");
-    println!("\nNote: Multi-word questions can be entered without quotes (e.g., yuchi hows you)");
-    println!("\nExamples:");
-    println!("  yuchi hi");
-    println!("  yuchi hows you");
-    println!("  yuchi --imagine a train station");
-    println!("  yuchi --image meme.jpg What's the text?");
-    println!("\nRun `yuchi --login` to authenticate first.");
+pub fn display_error(error: &YuchiError) {
+    eprintln!("{}", error.to_string().red().bold());
+    if let YuchiError::Http { body, .. } = error {
+        if !body.trim().is_empty() {
+            let body: String = body.chars().take(500).collect();
+            eprintln!("{}", format!("Response: {}", body).dimmed());
+        }
+    }
+    if let Some(hint) = error_hint(error) {
+        eprintln!("{}", format!("Hint: {}", hint).yellow());
+    }
 }
 
-pub fn display_error(error: &YuchiError) {
-    let error_message = match error {
-        YuchiError::Api(msg) => format!("API Error: {}", msg),
-        YuchiError::Config(msg) => format!("Config Error: {}", msg),
-        YuchiError::Input(msg) => format!("Input Error: {}", msg),
-        YuchiError::Image(msg) => format!("Image Error: {}", msg),
-        YuchiError::Tool(msg) => format!("Tool Error: {}", msg),
-    };
-    eprintln!("{}", error_message.red().bold());
+fn error_hint(error: &YuchiError) -> Option<String> {
+    match error {
+        YuchiError::Network { source, .. } if source.is_timeout() => {
+            Some("ShapesAI took too long to answer. Check your connection and try again.".to_string())
+        }
+        YuchiError::Network { .. } => {
+            Some("Could not reach ShapesAI. Check your internet connection, DNS and proxy settings.".to_string())
+        }
+        YuchiError::Http { status, .. } if status.is_server_error() => {
+            Some("ShapesAI is having trouble right now. Try again in a moment.".to_string())
+        }
+        YuchiError::Http { status, .. } if status.as_u16() == 404 => {
+            Some("Check the shape username with `yuchi shape <USERNAME>` or `--model`.".to_string())
+        }
+        YuchiError::RateLimited { retry_after: Some(secs) } => Some(format!("Retry in {} seconds.", secs)),
+        YuchiError::RateLimited { retry_after: None } => Some("Wait a bit before asking again.".to_string()),
+        YuchiError::Config(_) => Some("Run `yuchi whoami` to see the active profile and credentials.".to_string()),
+        YuchiError::Image(_) => Some("Only existing PNG and JPEG files can be attached.".to_string()),
+        YuchiError::Json { .. } | YuchiError::Api(_) => {
+            Some("ShapesAI sent something unexpected; trying again usually helps.".to_string())
+        }
+        _ => None,
+    }
 }
 
 pub fn display_progress() -> ProgressBar {
@@ -53,6 +63,88 @@ pub fn display_response(_question: &str, response: &str) {
     println!("{}", format!("Yuchi: {}", response).cyan());
 }
 
+/// Verbose-mode summary of where a question's time went, on stderr.
+pub fn display_latency(latency: &Latency, total: Duration) {
+    let requests: Vec<String> = latency
+        .requests
+        .iter()
+        .enumerate()
+        .map(|(i, elapsed)| format!("request {}: {} ms", i + 1, elapsed.as_millis()))
+        .collect();
+    let mut parts = requests;
+    if !latency.tools.is_zero() {
+        parts.push(format!("tools: {} ms", latency.tools.as_millis()));
+    }
+    parts.push(format!("total: {} ms", total.as_millis()));
+    eprintln!("{}", format!("Latency: {}", parts.join(", ")).dimmed());
+}
+
+/// Prints `prompt` and reads one trimmed line from stdin; a closed input cancels.
+pub fn read_line(prompt: &str) -> Result<String, YuchiError> {
+    print!("{}", prompt);
+    io::stdout().flush().map_err(|e| YuchiError::io("Failed to flush stdout", e))?;
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input).map_err(YuchiError::prompt)? == 0 {
+        return Err(YuchiError::Cancelled("Input was closed.".to_string()));
+    }
+    Ok(input.trim().to_string())
+}
+
+/// Asks a yes/no question on stdout; anything but `y` is a no.
+pub fn confirm(question: &str) -> Result<bool, YuchiError> {
+    Ok(read_line(&format!("{} (y/n): ", question))?.eq_ignore_ascii_case("y"))
+}
+
+/// A colored unified diff of a file about to be written; `old` is empty for new files.
+pub fn display_diff(path: &str, old: &str, new: &str) {
+    let diff = TextDiff::from_lines(old, new);
+    let mut unified = diff.unified_diff();
+    let unified = unified.context_radius(3).header(&format!("a/{}", path), &format!("b/{}", path));
+    for line in unified.to_string().lines() {
+        let line = if line.starts_with("+++") || line.starts_with("---") {
+            line.bold()
+        } else if line.starts_with('+') {
+            line.green()
+        } else if line.starts_with('-') {
+            line.red()
+        } else if line.starts_with("@@") {
+            line.cyan()
+        } else {
+            line.normal()
+        };
+        println!("{}", line);
+    }
+}
+
+/// One hunk of a patch under review, colored like `git diff`.
+pub fn display_hunk(hunk: &Hunk) {
+    println!("{}", hunk.header.cyan());
+    for line in &hunk.lines {
+        match line {
+            Line::Context(text) => println!(" {}", text),
+            Line::Remove(text) => println!("{}", format!("-{}", text).red()),
+            Line::Add(text) => println!("{}", format!("+{}", text).green()),
+        }
+    }
+}
+
+/// The first lines of what a tool is about to send, dimmed.
+pub fn display_preview(text: &str, max_lines: usize) {
+    for line in text.lines().take(max_lines) {
+        println!("{}", line.dimmed());
+    }
+    let more = text.lines().count().saturating_sub(max_lines);
+    if more > 0 {
+        println!("{}", format!("… {} more lines", more).dimmed());
+    }
+}
+
+/// Tells what was masked in `source` before it was sent.
+pub fn display_redactions(source: &str, found: &BTreeMap<String, usize>) {
+    let parts: Vec<String> = found.iter().map(|(kind, count)| format!("{} × {}", count, kind)).collect();
+    eprintln!("{}", format!("Redacted from {}: {}", source, parts.join(", ")).yellow());
+}
+
 pub fn display_command_result(command: &str, result: &str) {
     let mut table = Table::new();
     table.add_row(Row::new(vec![
@@ -65,3 +157,36 @@ pub fn display_command_result(command: &str, result: &str) {
     ]));
     table.printstd();
 }
+
+pub fn display_whoami(rows: &[(&str, String)]) {
+    let mut table = Table::new();
+    for (label, value) in rows {
+        table.add_row(Row::new(vec![
+            Cell::new(label).style_spec("bFc"),
+            Cell::new(value),
+        ]));
+    }
+    table.printstd();
+}
+
+pub fn display_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut table = Table::new();
+    table.add_row(Row::new(headers.iter().map(|h| Cell::new(h).style_spec("bFc")).collect()));
+    for row in rows {
+        table.add_row(Row::new(row.iter().map(|value| Cell::new(value)).collect()));
+    }
+    table.printstd();
+}
+
+pub fn display_settings(detail_header: &str, rows: &[(&str, String, String)]) {
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![
+        Cell::new("Key").style_spec("bFc"),
+        Cell::new("Value").style_spec("bFc"),
+        Cell::new(detail_header).style_spec("bFc"),
+    ]));
+    for (key, value, description) in rows {
+        table.add_row(Row::new(vec![Cell::new(key), Cell::new(value), Cell::new(description)]));
+    }
+    table.printstd();
+}
@@ -0,0 +1,298 @@
+use crate::agent;
+use crate::config::{self, Config};
+use crate::errors::YuchiError;
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rpassword::prompt_password;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Environment variable holding the vault passphrase for unattended use.
+pub const PASSPHRASE_ENV: &str = "YUCHI_VAULT_PASSPHRASE";
+
+const VAULT_VERSION: u32 = 1;
+
+/// The credentials kept out of the plaintext config file.
+#[derive(Serialize, Deserialize, Default, PartialEq)]
+pub struct Secrets {
+    pub api_key: Option<String>,
+    pub user_auth_token: Option<String>,
+}
+
+impl Secrets {
+    pub fn is_empty(&self) -> bool {
+        self.api_key.is_none() && self.user_auth_token.is_none()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct VaultFile {
+    version: u32,
+    kdf: String,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Key derived during this run, so a command that loads and then saves prompts only once.
+static KEY_CACHE: Mutex<Option<(String, [u8; 32])>> = Mutex::new(None);
+
+/// Vault of the active profile, next to its config file.
+pub fn vault_path() -> Result<PathBuf, YuchiError> {
+    let file_name = match config::profile() {
+        config::DEFAULT_PROFILE => "vault.json".to_string(),
+        other => format!("vault-{}.json", other),
+    };
+    Ok(Config::path()?.with_file_name(file_name))
+}
+
+/// Decrypts the vault, or returns `None` when no vault has been written yet.
+pub fn load() -> Result<Option<Secrets>, YuchiError> {
+    let path = vault_path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    check_private(&path)?;
+
+    let raw = fs::read_to_string(&path)
+        .map_err(|e| YuchiError::io(format!("Failed to read vault '{}'", path.display()), e))?;
+    let file: VaultFile = serde_json::from_str(&raw)
+        .map_err(|e| YuchiError::json(format!("Vault '{}' is corrupted", path.display()), e))?;
+    if file.version != VAULT_VERSION {
+        return Err(YuchiError::Config(format!(
+            "Vault '{}' has unsupported version {}.",
+            path.display(),
+            file.version
+        )));
+    }
+
+    let key = key_for(&file.salt, false)?;
+    let plaintext = decrypt(&file, &key, &path)?;
+
+    let secrets = serde_json::from_slice(&plaintext)
+        .map_err(|e| YuchiError::json(format!("Vault '{}' is corrupted", path.display()), e))?;
+    Ok(Some(secrets))
+}
+
+/// Encrypts `secrets` into the vault, removing the vault when there is nothing to keep.
+pub fn store(secrets: &Secrets) -> Result<(), YuchiError> {
+    if secrets.is_empty() {
+        return remove();
+    }
+
+    let path = vault_path()?;
+    // Keep the existing salt so a key cached by the agent stays valid, and make sure the key opens
+    // the vault, so a mistyped passphrase can't lock the credentials away under a new one.
+    let existing = fs::read_to_string(&path).ok().and_then(|raw| serde_json::from_str::<VaultFile>(&raw).ok());
+    let (salt, key) = match existing {
+        Some(file) => {
+            let key = key_for(&file.salt, false)?;
+            decrypt(&file, &key, &path)?;
+            (file.salt, key)
+        }
+        None => {
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            let salt = BASE64.encode(salt);
+            let key = key_for(&salt, true)?;
+            (salt, key)
+        }
+    };
+
+    let plaintext = serde_json::to_vec(secrets)
+        .map_err(|e| YuchiError::json("Failed to serialize secrets", e))?;
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key))
+        .encrypt(&nonce, plaintext.as_ref())
+        .map_err(|_| YuchiError::Config("Failed to encrypt secrets.".to_string()))?;
+
+    let file = VaultFile {
+        version: VAULT_VERSION,
+        kdf: "argon2id".to_string(),
+        salt,
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(ciphertext),
+    };
+    let json = serde_json::to_string_pretty(&file)
+        .map_err(|e| YuchiError::json("Failed to serialize vault", e))?;
+    write_private(&path, json.as_bytes())
+}
+
+pub fn remove() -> Result<(), YuchiError> {
+    let path = vault_path()?;
+    if path.exists() {
+        fs::remove_file(&path)
+            .map_err(|e| YuchiError::io(format!("Failed to remove vault '{}'", path.display()), e))?;
+    }
+    forget_key();
+    Ok(())
+}
+
+/// Derives the vault key and hands it to a background agent so later commands don't prompt.
+pub fn unlock(ttl_secs: u64) -> Result<(), YuchiError> {
+    let path = vault_path()?;
+    let salt = existing_salt(&path)
+        .ok_or_else(|| YuchiError::Config("No vault found. Run `yuchi login` first.".to_string()))?;
+    forget_key();
+    let key = key_for(&salt, false)?;
+    // Make sure the passphrase is right before caching the key.
+    load()?;
+    agent::start(&salt, &key, ttl_secs)
+}
+
+fn existing_salt(path: &Path) -> Option<String> {
+    let raw = fs::read_to_string(path).ok()?;
+    serde_json::from_str::<VaultFile>(&raw).ok().map(|f| f.salt)
+}
+
+fn forget_key() {
+    if let Ok(mut cache) = KEY_CACHE.lock() {
+        *cache = None;
+    }
+}
+
+/// Finds the key for `salt` in this process, the agent, the passphrase variable or a prompt, in that order.
+fn key_for(salt: &str, new_vault: bool) -> Result<[u8; 32], YuchiError> {
+    if let Ok(cache) = KEY_CACHE.lock() {
+        if let Some((cached_salt, key)) = cache.as_ref() {
+            if cached_salt == salt {
+                return Ok(*key);
+            }
+        }
+    }
+
+    let key = match agent::fetch(salt) {
+        Some(key) => key,
+        None => derive_key(&read_passphrase(new_vault)?, salt)?,
+    };
+    if let Ok(mut cache) = KEY_CACHE.lock() {
+        *cache = Some((salt.to_string(), key));
+    }
+    Ok(key)
+}
+
+fn read_passphrase(new_vault: bool) -> Result<String, YuchiError> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        if !passphrase.is_empty() {
+            return Ok(passphrase);
+        }
+    }
+    if !io::stdin().is_terminal() {
+        return Err(YuchiError::Config(format!(
+            "The credential vault is locked. Set {} or run `yuchi unlock` first.",
+            PASSPHRASE_ENV
+        )));
+    }
+
+    if new_vault {
+        let passphrase = prompt_password("Choose a passphrase to encrypt your credentials: ")
+            .map_err(YuchiError::prompt)?;
+        if passphrase.is_empty() {
+            return Err(YuchiError::Input("Vault passphrase cannot be empty".to_string()));
+        }
+        let confirmation = prompt_password("Repeat the passphrase: ")
+            .map_err(YuchiError::prompt)?;
+        if passphrase != confirmation {
+            return Err(YuchiError::Input("Passphrases do not match".to_string()));
+        }
+        Ok(passphrase)
+    } else {
+        prompt_password("Vault passphrase: ").map_err(YuchiError::prompt)
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &str) -> Result<[u8; 32], YuchiError> {
+    let salt = BASE64
+        .decode(salt)
+        .map_err(|e| YuchiError::Config(format!("Vault salt is corrupted: {}", e)))?;
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+        .map_err(|e| YuchiError::Config(format!("Failed to derive vault key: {}", e)))?;
+    Ok(key)
+}
+
+/// Decrypts the vault at `path`; a key that doesn't open it means the passphrase was wrong.
+fn decrypt(file: &VaultFile, key: &[u8; 32], path: &Path) -> Result<Vec<u8>, YuchiError> {
+    let nonce = decode(&file.nonce, path)?;
+    let ciphertext = decode(&file.ciphertext, path)?;
+    if nonce.len() != 12 {
+        return Err(YuchiError::Config(format!("Vault '{}' is corrupted: bad nonce.", path.display())));
+    }
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+        .map_err(|_| {
+            forget_key();
+            YuchiError::Config("Wrong vault passphrase.".to_string())
+        })
+}
+
+fn decode(value: &str, path: &Path) -> Result<Vec<u8>, YuchiError> {
+    BASE64
+        .decode(value)
+        .map_err(|e| YuchiError::Config(format!("Vault '{}' is corrupted: {}", path.display(), e)))
+}
+
+/// Writes `contents` to `path` so that only the current user can read it.
+pub fn write_private(path: &Path, contents: &[u8]) -> Result<(), YuchiError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| YuchiError::io(format!("Failed to create '{}'", parent.display()), e))?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .map_err(|e| YuchiError::io(format!("Failed to write '{}'", path.display()), e))?;
+    io::Write::write_all(&mut file, contents)
+        .map_err(|e| YuchiError::io(format!("Failed to write '{}'", path.display()), e))?;
+    set_private(path)
+}
+
+/// Restricts an existing file to mode 0600.
+pub fn set_private(path: &Path) -> Result<(), YuchiError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .map_err(|e| YuchiError::io(format!("Failed to set permissions on '{}'", path.display()), e))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Refuses files holding secrets that other users can read.
+pub fn check_private(path: &Path) -> Result<(), YuchiError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)
+            .map_err(|e| YuchiError::io(format!("Failed to inspect '{}'", path.display()), e))?
+            .permissions()
+            .mode();
+        if mode & 0o044 != 0 {
+            return Err(YuchiError::Config(format!(
+                "'{}' contains secrets but is readable by other users (mode {:o}). Run `chmod 600 {}` and try again.",
+                path.display(),
+                mode & 0o777,
+                path.display()
+            )));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}